@@ -0,0 +1,174 @@
+/*!
+An audit log of the raw payloads received by the server.
+
+Payloads are recorded exactly as they were received, before they're
+decompressed, reassembled, or parsed. Recording never blocks receiving:
+payloads are queued for a background thread to write, and if that queue
+is full then the payload isn't recorded.
+*/
+
+use std::{
+    cmp,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::mpsc::{self, SyncSender, TrySendError},
+    thread,
+};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+
+use crate::{
+    diagnostics::emit_err,
+    error::Error,
+    output::{FileSink, OutputSink, Rotation},
+};
+
+/**
+A handle to the audit log.
+
+Each line in the log is a JSON object with the `timestamp` the payload was
+received, the `peer` address it was received from, if known, and the
+`payload` itself as a lowercase hex string.
+*/
+#[derive(Clone)]
+pub(crate) struct Audit(SyncSender<Record>);
+
+struct Record {
+    timestamp: DateTime<Utc>,
+    peer: Option<SocketAddr>,
+    payload: Bytes,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp: DateTime<Utc>,
+    peer: Option<SocketAddr>,
+    payload: &'a str,
+}
+
+impl Audit {
+    /**
+    Open the audit log at `path` and start writing to it in the background.
+
+    At most `capacity` payloads are queued for writing at once. A capacity
+    of `0` is treated as `1`, so payloads can still be recorded.
+    The background thread completes once every handle has been dropped.
+    */
+    pub(crate) fn spawn(
+        path: impl Into<PathBuf>,
+        capacity: usize,
+    ) -> Result<(Self, thread::JoinHandle<()>), Error> {
+        let mut sink = FileSink::open(path, Rotation::Never, 0)?;
+
+        let (tx, rx) = mpsc::sync_channel::<Record>(cmp::max(capacity, 1));
+
+        let handle = thread::spawn(move || {
+            let mut hex = String::new();
+
+            for record in rx {
+                hex.clear();
+                encode_hex(&record.payload, &mut hex);
+
+                let line = serde_json::to_string(&Line {
+                    timestamp: record.timestamp,
+                    peer: record.peer,
+                    payload: &hex,
+                })
+                .expect("infallible JSON");
+
+                if let Err(err) = sink.send(&line) {
+                    increment!(audit_err);
+                    emit_err(&err, "GELF audit failed");
+                }
+            }
+        });
+
+        Ok((Audit(tx), handle))
+    }
+
+    /**
+    Record a payload received from `peer`.
+    */
+    pub(crate) fn record(&self, payload: &Bytes, peer: Option<SocketAddr>) {
+        let record = Record {
+            timestamp: Utc::now(),
+            peer,
+            payload: payload.clone(),
+        };
+
+        match self.0.try_send(record) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                increment!(audit_dropped)
+            }
+        }
+    }
+}
+
+fn encode_hex(bytes: &[u8], into: &mut String) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+    into.reserve(bytes.len() * 2);
+
+    for b in bytes {
+        into.push(DIGITS[(b >> 4) as usize] as char);
+        into.push(DIGITS[(b & 0xf) as usize] as char);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, fs};
+
+    use serde_json::Value;
+
+    #[test]
+    fn audit_records_raw_payloads() {
+        let path = env::temp_dir().join(format!("sqelf-audit-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let (audit, handle) = Audit::spawn(&path, 8).expect("failed to open audit log");
+
+        audit.record(&Bytes::from_static(b"{}"), Some("127.0.0.1:12201".parse().unwrap()));
+        audit.record(&Bytes::from_static(b"\x1f\x8b\0"), None);
+
+        drop(audit);
+        handle.join().unwrap();
+
+        let lines: Vec<Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(2, lines.len());
+
+        assert_eq!("7b7d", lines[0]["payload"]);
+        assert_eq!("127.0.0.1:12201", lines[0]["peer"]);
+
+        assert_eq!("1f8b00", lines[1]["payload"]);
+        assert_eq!(Value::Null, lines[1]["peer"]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn audit_with_zero_capacity_records_payloads() {
+        let path = env::temp_dir().join(format!("sqelf-audit-zero-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let (audit, handle) = Audit::spawn(&path, 0).expect("failed to open audit log");
+
+        audit.record(&Bytes::from_static(b"{}"), None);
+
+        drop(audit);
+        handle.join().unwrap();
+
+        assert_eq!(1, fs::read_to_string(&path).unwrap().lines().count());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
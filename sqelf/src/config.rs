@@ -1,17 +1,50 @@
-use std::{env, str::FromStr};
+use std::{env, fs, path::Path, str::FromStr};
 
-use crate::{Error, process, receive, server};
+use crate::{diagnostics, error::err_msg, Error, output, process, receive, replay, server};
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub receive: receive::Config,
     pub process: process::Config,
+    pub output: output::Config,
     pub server: server::Config,
+    pub replay: replay::Config,
+    pub diagnostics: diagnostics::Config,
 }
 
 impl Config {
+    /**
+    Read configuration from a TOML file.
+
+    Any fields that aren't present in the file use their default values.
+    */
+    pub fn from_toml_path(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+
+        let toml = fs::read_to_string(path)
+            .map_err(|e| err_msg(format_args!("failed to read config file `{}`: {}", path.display(), e)))?;
+
+        Self::from_toml_str(&toml)
+            .map_err(|e| err_msg(format_args!("failed to read config file `{}`: {}", path.display(), e)))
+    }
+
+    fn from_toml_str(toml: &str) -> Result<Self, Error> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    /**
+    Read configuration from the environment.
+
+    If the `CONFIG_FILE` environment variable is set then configuration is
+    read from that file first. Any other environment variables override
+    values in the file.
+    */
     pub fn from_env() -> Result<Self, Error> {
-        let mut config = Config::default();
+        let mut config = match parse_environment::<String>("CONFIG_FILE")? {
+            Some(path) => Config::from_toml_path(path)?,
+            None => Config::default(),
+        };
 
         let is_seq_app = is_seq_app();
         config.server.wait_on_stdin = is_seq_app;
@@ -22,7 +55,61 @@ impl Config {
             "GELF_ADDRESS"
         };
 
+        let metrics_address_var = if is_seq_app {
+            "SEQ_APP_SETTING_METRICSADDRESS"
+        } else {
+            "METRICS_ADDRESS"
+        };
+
         read_environment(&mut config.server.bind, bind_address_var)?;
+        read_environment_optional(&mut config.server.metrics_bind, metrics_address_var)?;
+
+        // Health checks are only useful for the container
+        if !is_seq_app {
+            read_environment_optional(&mut config.server.health_bind, "HEALTH_ADDRESS")?;
+        }
+
+        read_environment_optional(
+            &mut config.server.udp_recv_buffer_bytes,
+            "UDP_RECV_BUFFER_BYTES",
+        )?;
+
+        read_environment(&mut config.server.udp_reuse_port, "UDP_REUSE_PORT")?;
+        read_environment(&mut config.server.dual_stack, "DUAL_STACK")?;
+        read_environment_optional(
+            &mut config.server.udp_rate_limit_per_sec,
+            "UDP_RATE_LIMIT_PER_SEC",
+        )?;
+        read_environment_optional(
+            &mut config.server.udp_rate_limit_burst,
+            "UDP_RATE_LIMIT_BURST",
+        )?;
+        read_environment(&mut config.server.udp_split_on_null, "UDP_SPLIT_ON_NULL")?;
+        read_environment(&mut config.server.udp_max_size_bytes, "UDP_MAX_SIZE_BYTES")?;
+        read_environment_optional(&mut config.server.audit_path, "AUDIT_PATH")?;
+        read_environment(&mut config.server.audit_capacity, "AUDIT_CAPACITY")?;
+        read_environment(&mut config.server.sample_rate, "SAMPLE_RATE")?;
+        read_environment(
+            &mut config.server.queue_overflow_policy,
+            "QUEUE_OVERFLOW_POLICY",
+        )?;
+        read_environment(&mut config.server.selftest, "SELFTEST")?;
+        read_environment(&mut config.server.selftest_fatal, "SELFTEST_FATAL")?;
+        read_environment(&mut config.diagnostics.min_level, "LOG_LEVEL")?;
+        read_environment_optional(&mut config.diagnostics.instance_name, "INSTANCE_NAME")?;
+        read_environment(&mut config.process.dry_run, "DRY_RUN")?;
+        read_environment(&mut config.process.attach_source_ip, "ATTACH_SOURCE_IP")?;
+        read_environment(&mut config.output.kind, "OUTPUT")?;
+        read_environment_optional(&mut config.output.file_path, "OUTPUT_FILE_PATH")?;
+        read_environment(&mut config.output.file_rotation, "OUTPUT_FILE_ROTATION")?;
+        read_environment(&mut config.output.file_retained, "OUTPUT_FILE_RETAINED")?;
+        read_environment(&mut config.output.echo, "ECHO")?;
+        read_environment(&mut config.output.echo_only, "ECHO_ONLY")?;
+        read_environment(&mut config.process.input_format, "INPUT_FORMAT")?;
+        read_environment_optional(&mut config.replay.path, "REPLAY_PATH")?;
+        read_environment(&mut config.replay.framing, "REPLAY_FRAMING")?;
+        read_environment_optional(&mut config.replay.rate_per_sec, "REPLAY_RATE_PER_SEC")?;
+        read_environment(&mut config.replay.repeat, "REPLAY_REPEAT")?;
 
         Ok(config)
     }
@@ -33,22 +120,107 @@ fn is_seq_app() -> bool {
 }
 
 fn read_environment<T>(into: &mut T, name: impl AsRef<str>) -> Result<(), Error>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    if let Some(v) = parse_environment(name)? {
+        *into = v;
+    }
+
+    Ok(())
+}
+
+fn read_environment_optional<T>(into: &mut Option<T>, name: impl AsRef<str>) -> Result<(), Error>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    if let Some(v) = parse_environment(name)? {
+        *into = Some(v);
+    }
+
+    Ok(())
+}
+
+fn parse_environment<T>(name: impl AsRef<str>) -> Result<Option<T>, Error>
 where
     T: FromStr,
     T::Err: std::error::Error + Send + Sync + 'static,
 {
     match env::var(name.as_ref()) {
         // The environment variable exists, but is empty
-        Ok(ref v) if v == "" => return Ok(()),
+        Ok(ref v) if v.is_empty() => Ok(None),
         // The environment variable does not exist
-        Err(env::VarError::NotPresent) => return Ok(()),
+        Err(env::VarError::NotPresent) => Ok(None),
         // The environment variable is invalid
         Err(e) => Err(e)?,
         // The environment variable has a value
-        Ok(v) => {
-            *into = T::from_str(&v)?;
+        Ok(v) => Ok(Some(T::from_str(&v)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::server::{Bind, Binds};
+
+    #[test]
+    fn from_toml_str() {
+        let config = Config::from_toml_str(
+            r#"
+            [receive]
+            max_chunks_per_message = 64
+
+            [server]
+            bind = "udp://0.0.0.0:12201, unix:///var/run/sqelf.sock"
+            metrics_bind = "0.0.0.0:9100"
+            "#,
+        )
+        .expect("failed to read config");
+
+        assert_eq!(64, config.receive.max_chunks_per_message);
+        assert_eq!(
+            receive::Config::default().incomplete_timeout_ms,
+            config.receive.incomplete_timeout_ms
+        );
+
+        assert_eq!(
+            Binds(vec![
+                Bind::Udp("0.0.0.0:12201".to_owned(), None),
+                Bind::Unix("/var/run/sqelf.sock".into()),
+            ]),
+            config.server.bind
+        );
+        assert_eq!(Some("0.0.0.0:9100"), config.server.metrics_bind.as_deref());
+        assert_eq!(None, config.server.health_bind);
+    }
+
+    #[test]
+    fn from_toml_str_empty() {
+        let config = Config::from_toml_str("").expect("failed to read config");
+
+        assert_eq!(server::Config::default().bind, config.server.bind);
+    }
+
+    #[test]
+    fn from_toml_str_invalid() {
+        let cases = vec![
+            ("[receive]\nmax_chunks_per_message = \"lots\"", "max_chunks_per_message"),
+            ("[server]\nnot_a_field = 1", "not_a_field"),
+            ("[server]\nbind = ", "line 2"),
+        ];
+
+        for (toml, expected) in cases {
+            let err = Config::from_toml_str(toml).expect_err("expected an invalid config to fail");
 
-            Ok(())
+            assert!(
+                err.to_string().contains(expected),
+                "expected `{}` to mention `{}`",
+                err,
+                expected
+            );
         }
     }
 }
@@ -1,5 +1,146 @@
 use chrono::{DateTime, Utc};
-use std::fmt::Display;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    env,
+    fmt::{self, Display},
+    fs,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+/**
+Diagnostics configuration.
+*/
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /**
+    The minimum level of diagnostic events to emit.
+
+    Events below this level are discarded.
+    */
+    pub min_level: Level,
+    /**
+    A name for this instance of `sqelf`.
+
+    The name is attached to every diagnostic event as `instance`
+    and to every Prometheus metric as an `instance` label.
+    If no name is given then the hostname is used.
+    */
+    pub instance_name: Option<String>,
+}
+
+/**
+The level of a diagnostic event.
+
+Levels are ordered from most to least severe.
+*/
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    #[default]
+    Debug = 3,
+    Trace = 4,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = ParseLevelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_ascii_lowercase() {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(ParseLevelError(s.to_owned())),
+        }
+    }
+}
+
+/**
+A diagnostic level that isn't supported.
+*/
+#[derive(Debug)]
+pub struct ParseLevelError(String);
+
+impl fmt::Display for ParseLevelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` isn't a supported level; expected `error`, `warn`, `info`, `debug`, or `trace`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseLevelError {}
+
+static MIN_LEVEL: AtomicUsize = AtomicUsize::new(Level::Debug as usize);
+
+/**
+Set the minimum level of diagnostic events to emit.
+*/
+pub fn set_min_level(level: Level) {
+    MIN_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+/**
+Whether diagnostic events at the given level are emitted.
+
+This is a single atomic load, so it's cheap to check before doing
+any work to build an event.
+*/
+pub fn is_enabled(level: Level) -> bool {
+    level as usize <= MIN_LEVEL.load(Ordering::Relaxed)
+}
+
+static INSTANCE_NAME: OnceLock<String> = OnceLock::new();
+
+/**
+Set the name of this instance to attach to diagnostic events and metrics.
+
+If `name` is `None` then the hostname is used, if it can be determined.
+The name can only be set once; later calls are ignored.
+*/
+pub fn set_instance_name(name: Option<String>) {
+    if let Some(name) = name.or_else(hostname) {
+        let _ = INSTANCE_NAME.set(name);
+    }
+}
+
+fn instance_name() -> Option<&'static str> {
+    INSTANCE_NAME.get().map(String::as_str)
+}
+
+fn hostname() -> Option<String> {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|hostname| hostname.trim().to_owned())
+        .filter(|hostname| !hostname.is_empty())
+}
 
 #[derive(Serialize)]
 struct DiagnosticEvent<'a> {
@@ -15,38 +156,590 @@ struct DiagnosticEvent<'a> {
     #[serde(rename = "@x")]
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<&'a str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<&'static str>,
+
+    #[serde(flatten)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    properties: Option<Value>,
 }
 
 impl<'a> DiagnosticEvent<'a> {
     pub fn new(
-        level: &'static str,
+        level: Level,
         error: Option<&'a str>,
         message_template: &'static str,
     ) -> DiagnosticEvent<'a> {
         DiagnosticEvent {
             timestamp: Utc::now(),
             message_template,
-            level,
+            level: level.as_str(),
             error,
+            instance: instance_name(),
+            properties: None,
+        }
+    }
+}
+
+/**
+A monotonically increasing counter.
+*/
+pub(crate) struct Counter(AtomicUsize);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicUsize::new(0))
+    }
+
+    pub(crate) fn increment(&self) {
+        self.add(1);
+    }
+
+    pub(crate) fn add(&self, value: usize) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+macro_rules! metrics {
+    ($(#[doc = $help:expr] $metric:ident,)*) => {
+        /**
+        Counters for events in the GELF server.
+        */
+        pub(crate) struct Metrics {
+            $(#[doc = $help] pub(crate) $metric: Counter,)*
+        }
+
+        pub(crate) static METRICS: Metrics = Metrics {
+            $($metric: Counter::new(),)*
+        };
+
+        impl Metrics {
+            fn snapshot(&self) -> Vec<Metric> {
+                vec![$(Metric {
+                    name: stringify!($metric),
+                    help: $help.trim(),
+                    value: self.$metric.get(),
+                },)*]
+            }
+        }
+    };
+}
+
+/**
+The value of a metric at a point in time.
+*/
+struct Metric {
+    name: &'static str,
+    help: &'static str,
+    value: usize,
+}
+
+metrics! {
+    /** Payloads that were received successfully. */
+    receive_ok,
+    /** Payloads that failed to be decoded. */
+    receive_err,
+    /** Chunked messages that failed to be reassembled. */
+    reassembly_err,
+    /** Compressed messages that failed to be decompressed. */
+    decompress_err,
+    /** Messages that failed to be parsed as GELF. */
+    parse_err,
+    /** Payloads that were skipped because they were empty. */
+    receive_empty,
+    /** Datagrams that failed to be read from a socket. */
+    socket_err,
+    /** Datagrams that were dropped by the kernel before they could be read, on Linux. */
+    udp_kernel_drops,
+    /** Datagrams that were dropped because their source exceeded the rate limit. */
+    rate_limited,
+    /** Datagrams that were dropped because they exceeded the maximum size. */
+    udp_msg_overflow,
+    /** Messages that were rejected because they had a missing or unrecognized GELF version. */
+    receive_invalid_version,
+    /** Messages that were dropped because they weren't sampled. */
+    sampled_out,
+    /** Messages that were dropped by a transform before being processed. */
+    transform_dropped,
+    /** Messages that had properties dropped or truncated because they had too many, were too deep, or had values longer than `max_field_value_bytes`. */
+    process_truncated,
+    /** Messages that were processed successfully. */
+    process_ok,
+    /** Messages that failed to be processed. */
+    process_err,
+    /** Messages that were dropped on arrival because too many were waiting to be processed. */
+    process_queue_full,
+    /** Messages that had to wait because too many were waiting to be processed. */
+    process_queue_blocked,
+    /** Messages that were dropped to make space for newer ones because too many were waiting to be processed. */
+    process_queue_evicted,
+    /** Chunked messages that were fully reassembled. */
+    chunk_reassembled,
+    /** Chunked messages that expired before all their chunks arrived. */
+    chunk_expired,
+    /** Chunked messages that were evicted to make room for newer ones before all their chunks arrived. */
+    chunk_evicted,
+    /** Chunks that were ignored because a chunk with the same sequence number had already arrived. */
+    chunk_duplicate,
+    /** Compressed messages that decompressed past the maximum size. */
+    decompress_overflow,
+    /** Messages with a missing or invalid timestamp that were given the time they were received. */
+    timestamp_defaulted,
+    /** Messages without a host that were given an unknown host. */
+    host_defaulted,
+    /** Raw payloads that weren't recorded in the audit log because it was falling behind. */
+    audit_dropped,
+    /** Raw payloads that couldn't be written to the audit log. */
+    audit_err,
+}
+
+/**
+A histogram of observed values.
+
+Each value is counted in the first bucket whose upper bound it doesn't exceed.
+Values greater than every bound are counted in a final overflow bucket.
+*/
+pub(crate) struct Histogram {
+    bounds: [usize; Histogram::BUCKETS],
+    buckets: [Counter; Histogram::BUCKETS + 1],
+    sum: Counter,
+}
+
+impl Histogram {
+    const BUCKETS: usize = 6;
+
+    const fn new(bounds: [usize; Histogram::BUCKETS]) -> Self {
+        // Each use of the constant is a new counter
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: Counter = Counter::new();
+
+        Histogram {
+            bounds,
+            buckets: [ZERO; Histogram::BUCKETS + 1],
+            sum: ZERO,
+        }
+    }
+
+    pub(crate) fn observe(&self, value: usize) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(Histogram::BUCKETS);
+
+        self.buckets[bucket].increment();
+        self.sum.add(value);
+    }
+
+    /**
+    Get the cumulative count of values in each bucket, along with the bucket's upper bound.
+
+    The last bucket has no upper bound, so its count is the total number of values.
+    */
+    fn cumulative(&self) -> impl Iterator<Item = (Option<usize>, usize)> + '_ {
+        let bounds = self.bounds.iter().map(|bound| Some(*bound)).chain(Some(None));
+
+        bounds
+            .zip(self.buckets.iter())
+            .scan(0, |count, (bound, bucket)| {
+                *count += bucket.get();
+
+                Some((bound, *count))
+            })
+    }
+}
+
+/**
+The sizes in bytes of datagrams that were received.
+*/
+pub(crate) static MESSAGE_SIZE_BYTES: Histogram = Histogram::new([
+    256,
+    1024,
+    4 * 1024,
+    16 * 1024,
+    64 * 1024,
+    256 * 1024,
+]);
+
+/**
+The rate of a metric over a window of time.
+*/
+struct Rate {
+    name: &'static str,
+    help: &'static str,
+    per_sec: f64,
+}
+
+/**
+A moving window of samples of the metrics that rates are calculated for.
+
+Samples are taken periodically off the hot path, so incrementing a
+metric is still just an atomic add.
+*/
+pub(crate) struct Rates {
+    samples: VecDeque<(Instant, [usize; 2])>,
+}
+
+pub(crate) static RATES: Mutex<Rates> = Mutex::new(Rates::new());
+
+impl Rates {
+    const fn new() -> Self {
+        Rates {
+            samples: VecDeque::new(),
+        }
+    }
+
+    /**
+    Take a sample of the current value of metrics.
+
+    Samples older than `window` are discarded.
+    */
+    pub(crate) fn sample(&mut self, window: Duration) {
+        self.sample_at(
+            Instant::now(),
+            window,
+            [METRICS.receive_ok.get(), METRICS.process_ok.get()],
+        );
+    }
+
+    fn sample_at(&mut self, now: Instant, window: Duration, values: [usize; 2]) {
+        self.samples.push_back((now, values));
+
+        while let Some((ts, _)) = self.samples.front() {
+            if now.duration_since(*ts) <= window {
+                break;
+            }
+
+            self.samples.pop_front();
+        }
+    }
+
+    fn snapshot(&self) -> Vec<Rate> {
+        let per_sec = |i: usize| match (self.samples.front(), self.samples.back()) {
+            (Some((start, start_values)), Some((end, end_values))) if end > start => {
+                let elapsed = end.duration_since(*start).as_secs_f64();
+
+                end_values[i].saturating_sub(start_values[i]) as f64 / elapsed
+            }
+            _ => 0.0,
+        };
+
+        vec![
+            Rate {
+                name: "receive_ok_per_sec",
+                help: "Payloads that were received successfully per second.",
+                per_sec: per_sec(0),
+            },
+            Rate {
+                name: "process_ok_per_sec",
+                help: "Messages that were processed successfully per second.",
+                per_sec: per_sec(1),
+            },
+        ]
+    }
+}
+
+fn rates() -> Vec<Rate> {
+    RATES
+        .lock()
+        .map(|rates| rates.snapshot())
+        .unwrap_or_default()
+}
+
+/**
+The health of the GELF server.
+*/
+pub(crate) struct Health(AtomicUsize);
+
+pub(crate) static HEALTH: Health = Health::new();
+
+impl Health {
+    const PENDING: usize = 0;
+    const READY: usize = 1;
+    const UNAVAILABLE: usize = 2;
+
+    const fn new() -> Self {
+        Health(AtomicUsize::new(Self::PENDING))
+    }
+
+    /**
+    The server has been bound and is receiving messages.
+    */
+    pub(crate) fn ready(&self) {
+        self.0.store(Self::READY, Ordering::Relaxed);
+    }
+
+    /**
+    The server is shutting down or has failed.
+    */
+    pub(crate) fn unavailable(&self) {
+        self.0.store(Self::UNAVAILABLE, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_ready(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == Self::READY
+    }
+}
+
+/**
+Increment a metric counter.
+*/
+macro_rules! increment {
+    ($metric:ident) => {
+        $crate::diagnostics::METRICS.$metric.increment()
+    };
+}
+
+#[derive(Serialize)]
+struct MetricsEvent<'a> {
+    #[serde(flatten)]
+    evt: DiagnosticEvent<'a>,
+
+    #[serde(flatten)]
+    metrics: BTreeMap<&'static str, usize>,
+
+    #[serde(flatten)]
+    rates: BTreeMap<&'static str, f64>,
+}
+
+/**
+Format the current value of all metrics using the Prometheus text format.
+
+If an instance name is set then it's attached to every metric as a label.
+*/
+pub(crate) fn prometheus_metrics() -> String {
+    use std::fmt::Write;
+
+    let labels = PrometheusLabels::new(instance_name());
+
+    let mut text = String::new();
+
+    for metric in METRICS.snapshot() {
+        let _ = writeln!(text, "# HELP sqelf_{} {}", metric.name, metric.help);
+        let _ = writeln!(text, "# TYPE sqelf_{} counter", metric.name);
+        let _ = writeln!(text, "sqelf_{}{} {}", metric.name, labels.only(), metric.value);
+    }
+
+    for rate in rates() {
+        let _ = writeln!(text, "# HELP sqelf_{} {}", rate.name, rate.help);
+        let _ = writeln!(text, "# TYPE sqelf_{} gauge", rate.name);
+        let _ = writeln!(text, "sqelf_{}{} {}", rate.name, labels.only(), rate.per_sec);
+    }
+
+    prometheus_histogram(
+        &mut text,
+        "message_size_bytes",
+        "The sizes in bytes of datagrams that were received.",
+        &labels,
+        &MESSAGE_SIZE_BYTES,
+    );
+
+    text
+}
+
+/**
+Constant labels attached to every Prometheus metric.
+*/
+struct PrometheusLabels(Option<String>);
+
+impl PrometheusLabels {
+    fn new(instance: Option<&str>) -> Self {
+        PrometheusLabels(instance.map(|instance| {
+            let instance = instance
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+
+            format!("instance=\"{}\"", instance)
+        }))
+    }
+
+    /**
+    The labels on their own, like `{instance="a"}`.
+    */
+    fn only(&self) -> String {
+        self.0
+            .as_ref()
+            .map(|labels| format!("{{{}}}", labels))
+            .unwrap_or_default()
+    }
+
+    /**
+    The labels followed by a trailing comma, like `instance="a",`.
+    */
+    fn prefix(&self) -> String {
+        self.0
+            .as_ref()
+            .map(|labels| format!("{},", labels))
+            .unwrap_or_default()
+    }
+}
+
+fn prometheus_histogram(
+    text: &mut String,
+    name: &str,
+    help: &str,
+    labels: &PrometheusLabels,
+    histogram: &Histogram,
+) {
+    use std::fmt::Write;
+
+    let _ = writeln!(text, "# HELP sqelf_{} {}", name, help);
+    let _ = writeln!(text, "# TYPE sqelf_{} histogram", name);
+
+    let mut total = 0;
+    for (bound, count) in histogram.cumulative() {
+        match bound {
+            Some(bound) => {
+                let _ = writeln!(
+                    text,
+                    "sqelf_{}_bucket{{{}le=\"{}\"}} {}",
+                    name,
+                    labels.prefix(),
+                    bound,
+                    count
+                );
+            }
+            None => {
+                let _ = writeln!(
+                    text,
+                    "sqelf_{}_bucket{{{}le=\"+Inf\"}} {}",
+                    name,
+                    labels.prefix(),
+                    count
+                );
+                total = count;
+            }
         }
     }
+
+    let _ = writeln!(text, "sqelf_{}_sum{} {}", name, labels.only(), histogram.sum.get());
+    let _ = writeln!(text, "sqelf_{}_count{} {}", name, labels.only(), total);
+}
+
+/**
+Emit the current value of all metrics as a diagnostic event.
+*/
+pub fn emit_metrics() {
+    if !is_enabled(Level::Debug) {
+        return;
+    }
+
+    let evt = MetricsEvent {
+        evt: DiagnosticEvent::new(Level::Debug, None, "Collected GELF server metrics"),
+        metrics: METRICS
+            .snapshot()
+            .into_iter()
+            .map(|metric| (metric.name, metric.value))
+            .collect(),
+        rates: rates()
+            .into_iter()
+            .map(|rate| (rate.name, rate.per_sec))
+            .collect(),
+    };
+    let json = serde_json::to_string(&evt).expect("infallible JSON");
+    eprintln!("{}", json);
+}
+
+/**
+Write the current value of all metrics to `stderr` as a table.
+
+Unlike `emit_metrics`, this is meant to be read by a person
+inspecting a running server.
+*/
+pub fn dump_metrics() {
+    eprint!("{}", metrics_table());
+}
+
+fn metrics_table() -> String {
+    use std::fmt::Write;
+
+    let metrics = METRICS.snapshot();
+    let rates = rates();
+
+    let width = metrics
+        .iter()
+        .map(|metric| metric.name.len())
+        .chain(rates.iter().map(|rate| rate.name.len()))
+        .max()
+        .unwrap_or(0);
+
+    let mut table = String::new();
+
+    let _ = writeln!(table, "{:<width$}  value", "metric", width = width);
+    let _ = writeln!(table, "{:-<width$}  -----", "", width = width);
+
+    for metric in metrics {
+        let _ = writeln!(table, "{:<width$}  {}", metric.name, metric.value, width = width);
+    }
+
+    for rate in rates {
+        let _ = writeln!(table, "{:<width$}  {:.2}", rate.name, rate.per_sec, width = width);
+    }
+
+    table
 }
 
 pub fn emit(message_template: &'static str) {
-    let evt = DiagnosticEvent::new("DEBUG", None, &message_template);
+    if !is_enabled(Level::Debug) {
+        return;
+    }
+
+    let evt = DiagnosticEvent::new(Level::Debug, None, message_template);
     let json = serde_json::to_string(&evt).expect("infallible JSON");
     eprintln!("{}", json);
 }
 
+/**
+Emit a high-frequency diagnostic event, like one for each connection.
+
+The properties are only built if trace events are enabled.
+*/
+pub fn emit_trace(message_template: &'static str, properties: impl FnOnce() -> Value) {
+    emit_with_properties(Level::Trace, message_template, properties)
+}
+
+pub fn emit_debug(message_template: &'static str, properties: Value) {
+    emit_with_properties(Level::Debug, message_template, || properties)
+}
+
+pub fn emit_info(message_template: &'static str, properties: Value) {
+    emit_with_properties(Level::Info, message_template, || properties)
+}
+
+pub fn emit_warn(message_template: &'static str, properties: Value) {
+    emit_with_properties(Level::Warn, message_template, || properties)
+}
+
 pub fn emit_err(error: &impl Display, message_template: &'static str) {
+    if !is_enabled(Level::Error) {
+        return;
+    }
+
     let err_str = format!("{}", error);
-    let evt = DiagnosticEvent::new("ERROR", Some(&err_str), &message_template);
+    let evt = DiagnosticEvent::new(Level::Error, Some(&err_str), message_template);
+    let json = serde_json::to_string(&evt).expect("infallible JSON");
+    eprintln!("{}", json);
+}
+
+fn emit_with_properties(level: Level, message_template: &'static str, properties: impl FnOnce() -> Value) {
+    if !is_enabled(level) {
+        return;
+    }
+
+    let mut evt = DiagnosticEvent::new(level, None, message_template);
+    evt.properties = Some(properties());
     let json = serde_json::to_string(&evt).expect("infallible JSON");
     eprintln!("{}", json);
 }
 
 /// For use with `map_err`
-pub(crate) fn emit_abort<TInner>(message_template: &'static str) -> impl Fn(TInner) -> ()
+pub(crate) fn emit_abort<TInner>(message_template: &'static str) -> impl Fn(TInner)
 where
     TInner: Display,
 {
@@ -65,27 +758,107 @@ where
     }
 }
 
-/// For use with `or_else`
-pub(crate) fn emit_continue<TInner, TOuter>(
-    message_template: &'static str,
-) -> impl Fn(TInner) -> Result<(), TOuter>
-where
-    TInner: Display,
-{
-    emit_continue_with(message_template, || ())
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-/// For use with `or_else`
-pub(crate) fn emit_continue_with<TInner, TOk, TOuter>(
-    message_template: &'static str,
-    ok: impl Fn() -> TOk,
-) -> impl Fn(TInner) -> Result<TOk, TOuter>
-where
-    TInner: Display,
-{
-    move |err| {
-        emit_err(&err, message_template);
+    #[test]
+    fn metrics_table_aligned() {
+        let table = metrics_table();
+        let mut lines = table.lines();
+
+        let header = lines.next().expect("missing header");
+        let column = header.find("value").expect("missing value column");
+
+        assert!(lines.next().expect("missing separator").starts_with("---"));
+
+        for line in lines {
+            assert!(line.is_char_boundary(column));
+            assert_eq!("  ", &line[column - 2..column], "misaligned line `{}`", line);
+        }
+
+        assert!(table.contains("receive_ok "));
+        assert!(table.contains("receive_ok_per_sec "));
+    }
+
+    #[test]
+    fn rates_over_window() {
+        let window = Duration::from_secs(10);
+        let start = Instant::now();
+
+        let mut rates = Rates::new();
+
+        let per_sec = |rates: &Rates| -> Vec<f64> {
+            rates.snapshot().into_iter().map(|rate| rate.per_sec).collect()
+        };
+
+        rates.sample_at(start, window, [0, 0]);
+        assert_eq!(vec![0.0, 0.0], per_sec(&rates));
+
+        rates.sample_at(start + Duration::from_secs(5), window, [50, 25]);
+        assert_eq!(vec![10.0, 5.0], per_sec(&rates));
+
+        rates.sample_at(start + Duration::from_secs(10), window, [100, 50]);
+        assert_eq!(vec![10.0, 5.0], per_sec(&rates));
+
+        // The first sample falls out of the window
+        rates.sample_at(start + Duration::from_secs(15), window, [250, 50]);
+        assert_eq!(3, rates.samples.len());
+        assert_eq!(vec![20.0, 2.5], per_sec(&rates));
+    }
+
+    #[test]
+    fn histogram_prometheus() {
+        let histogram = Histogram::new([1, 2, 4, 8, 16, 32]);
+
+        for value in [0, 1, 3, 3, 16, 100] {
+            histogram.observe(value);
+        }
+
+        let mut text = String::new();
+        prometheus_histogram(
+            &mut text,
+            "test",
+            "A test histogram.",
+            &PrometheusLabels::new(None),
+            &histogram,
+        );
+
+        let expected = "\
+# HELP sqelf_test A test histogram.
+# TYPE sqelf_test histogram
+sqelf_test_bucket{le=\"1\"} 2
+sqelf_test_bucket{le=\"2\"} 2
+sqelf_test_bucket{le=\"4\"} 4
+sqelf_test_bucket{le=\"8\"} 4
+sqelf_test_bucket{le=\"16\"} 5
+sqelf_test_bucket{le=\"32\"} 5
+sqelf_test_bucket{le=\"+Inf\"} 6
+sqelf_test_sum 123
+sqelf_test_count 6
+";
+
+        assert_eq!(expected, text);
+    }
+
+    #[test]
+    fn prometheus_instance_label() {
+        let histogram = Histogram::new([1, 2, 4, 8, 16, 32]);
+        histogram.observe(0);
+
+        let mut text = String::new();
+        prometheus_histogram(
+            &mut text,
+            "test",
+            "A test histogram.",
+            &PrometheusLabels::new(Some("ingest-\"a\"")),
+            &histogram,
+        );
+
+        let lines: Vec<_> = text.lines().collect();
 
-        Ok(ok())
+        assert_eq!("sqelf_test_bucket{instance=\"ingest-\\\"a\\\"\",le=\"1\"} 1", lines[2]);
+        assert_eq!("sqelf_test_sum{instance=\"ingest-\\\"a\\\"\"} 0", lines[lines.len() - 2]);
+        assert_eq!("sqelf_test_count{instance=\"ingest-\\\"a\\\"\"} 1", lines[lines.len() - 1]);
     }
 }
@@ -4,12 +4,48 @@ use std::{
     any::Any,
 };
 
-pub(crate) type StdError = Box<error::Error + Send + Sync>;
+pub(crate) type StdError = Box<dyn error::Error + Send + Sync>;
 
-pub struct Error(Inner);
+pub struct Error(Inner, ErrorKind);
 
 struct Inner(String);
 
+/**
+The broad category of an error.
+
+This is used to count errors by what went wrong, without
+needing to inspect their messages.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorKind {
+    /**
+    A chunked message couldn't be reassembled.
+    */
+    Reassembly,
+    /**
+    A compressed message couldn't be decompressed.
+    */
+    Decompress,
+    /**
+    A message couldn't be parsed as GELF.
+    */
+    Parse,
+    /**
+    Any other error.
+    */
+    Other,
+}
+
+impl Error {
+    pub(crate) fn kind(&self) -> ErrorKind {
+        self.1
+    }
+
+    pub(crate) fn with_kind(self, kind: ErrorKind) -> Self {
+        Error(self.0, kind)
+    }
+}
+
 impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.0.fmt(f)
@@ -43,7 +79,7 @@ where
     E: error::Error,
 {
     fn from(err: E) -> Error {
-        Error(Inner(err.to_string()))
+        Error(Inner(err.to_string()), ErrorKind::Other)
     }
 }
 
@@ -54,16 +90,16 @@ impl From<Error> for StdError {
 }
 
 pub(crate) fn err_msg(msg: impl fmt::Display) -> Error {
-    Error(Inner(msg.to_string()))
+    Error(Inner(msg.to_string()), ErrorKind::Other)
 }
 
 pub(crate) fn unwrap_panic(panic: Box<dyn Any + Send + 'static>) ->  Error {
     if let Some(err) = panic.downcast_ref::<&str>() {
-        return Error(Inner((*err).into()));
+        return Error(Inner((*err).into()), ErrorKind::Other);
     }
 
     if let Some(err) = panic.downcast_ref::<String>() {
-        return Error(Inner((*err).clone()))
+        return Error(Inner((*err).clone()), ErrorKind::Other)
     }
 
     err_msg("unexpected panic (this is a bug)")
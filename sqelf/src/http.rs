@@ -0,0 +1,425 @@
+/*!
+A minimal HTTP server for exposing metrics and health checks, and for ingesting GELF.
+
+This isn't a general purpose HTTP server. It reads a single request from a
+connection and writes a complete response before closing it.
+*/
+
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use futures::future::{self, Either, Loop};
+
+use tokio::{
+    io,
+    net::{TcpListener, TcpStream},
+    prelude::*,
+};
+
+use crate::{diagnostics::*, error::Error, server::resolve_addr};
+
+/**
+The maximum number of bytes in the body of a request for metrics or health checks.
+
+These requests don't need a body, so any more than this is rejected.
+*/
+const MAX_DIAGNOSTICS_BODY_BYTES: usize = 1024;
+
+/**
+The time allowed to read a request and write its response.
+*/
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/**
+The maximum number of bytes in the request line and headers of a GELF request.
+*/
+const MAX_HEAD_BYTES: usize = 8 * 1024;
+
+/**
+The number of bytes to read from a GELF request at a time.
+*/
+const READ_BYTES: usize = 8 * 1024;
+
+/**
+The path that GELF messages are posted to.
+*/
+const INGEST_PATH: &str = "/gelf";
+
+const BAD_REQUEST: &str = "400 Bad Request";
+const PAYLOAD_TOO_LARGE: &str = "413 Payload Too Large";
+const UNSUPPORTED_MEDIA_TYPE: &str = "415 Unsupported Media Type";
+const HEADERS_TOO_LARGE: &str = "431 Request Header Fields Too Large";
+
+/**
+Bind an HTTP server for diagnostics.
+
+The server is returned as a future that should be spawned.
+It doesn't complete until the runtime shuts down.
+*/
+pub(crate) fn bind(addr: &str) -> Result<impl Future<Item = (), Error = ()>, Error> {
+    let addr = resolve_addr(addr)?;
+    let listener = TcpListener::bind(&addr)?;
+
+    Ok(listener
+        .incoming()
+        // Failing to accept a single connection shouldn't stop the server
+        .then(|conn| Ok::<_, ()>(conn.map_err(|e| emit_err(&e, "HTTP accept failed")).ok()))
+        .filter_map(|conn| conn)
+        .for_each(|conn| {
+            tokio::spawn(respond(conn));
+
+            Ok(())
+        }))
+}
+
+fn respond(conn: TcpStream) -> impl Future<Item = (), Error = ()> {
+    // The request may arrive over several reads, so wait for all of it
+    read_request(conn, MAX_DIAGNOSTICS_BODY_BYTES)
+        .and_then(|(conn, req)| io::write_all(conn, route(req)))
+        .and_then(|(conn, _)| io::shutdown(conn))
+        .map(|_| ())
+        .timeout(REQUEST_TIMEOUT)
+        // Errors on individual connections aren't interesting
+        .map_err(|_| ())
+}
+
+fn route(req: Result<Request, &'static str>) -> Vec<u8> {
+    let req = match req {
+        Ok(req) => req,
+        Err(status) => return response(status, "text/plain", ""),
+    };
+
+    match (&*req.method, &*req.path) {
+        ("GET", "/metrics") => response(
+            "200 OK",
+            "text/plain; version=0.0.4",
+            prometheus_metrics(),
+        ),
+        ("GET", "/health") if HEALTH.is_ready() => {
+            response("200 OK", "text/plain", "Healthy\n")
+        }
+        ("GET", "/health") => {
+            response("503 Service Unavailable", "text/plain", "Unavailable\n")
+        }
+        ("GET", _) => response("404 Not Found", "text/plain", "Not Found\n"),
+        _ => response("400 Bad Request", "text/plain", "Bad Request\n"),
+    }
+}
+
+fn response(status: &str, content_type: &str, body: impl AsRef<str>) -> Vec<u8> {
+    let body = body.as_ref();
+
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+/**
+Read a POSTed GELF message from a connection and respond to it.
+
+The body of the request is passed to `receive`. If it returns `Ok` then the
+request is accepted with `202 Accepted`. Messages are processed after the
+response is sent, so an accepted message may still fail to be processed or
+be dropped. If `receive` returns `Err`, or the body is empty, then the request
+is rejected with `400 Bad Request`.
+
+Bodies may be sent with a `Content-Length` or `Transfer-Encoding: chunked`.
+Compressed bodies are detected by `receive` the same way as compressed
+datagrams, so `Content-Encoding` is only checked for values that can't be read.
+*/
+pub(crate) fn ingest<T>(
+    conn: TcpStream,
+    max_body_bytes: usize,
+    receive: impl FnOnce(Bytes) -> Result<Option<T>, ()>,
+) -> impl Future<Item = Option<T>, Error = ()> {
+    read_request(conn, max_body_bytes)
+        .and_then(move |(conn, req)| {
+            let (status, msg) = match req {
+                Ok(ref req) if req.path != INGEST_PATH => ("404 Not Found", None),
+                Ok(ref req) if req.method != "POST" => ("405 Method Not Allowed", None),
+                Ok(ref req) if req.body.is_empty() => (BAD_REQUEST, None),
+                Ok(req) => match receive(req.body.into()) {
+                    Ok(msg) => ("202 Accepted", msg),
+                    Err(()) => (BAD_REQUEST, None),
+                },
+                Err(status) => (status, None),
+            };
+
+            io::write_all(conn, response(status, "text/plain", "")).map(move |(conn, _)| (conn, msg))
+        })
+        .and_then(|(conn, msg)| io::shutdown(conn).map(move |_| msg))
+        .timeout(REQUEST_TIMEOUT)
+        // Errors on individual connections aren't interesting
+        .then(|r| Ok(r.ok().and_then(|msg| msg)))
+}
+
+fn read_request(
+    conn: TcpStream,
+    max_body_bytes: usize,
+) -> impl Future<Item = (TcpStream, Result<Request, &'static str>), Error = io::Error> {
+    future::loop_fn((conn, Vec::new(), false), move |(conn, mut buf, continued)| {
+        io::read(conn, vec![0; READ_BYTES]).and_then(move |(conn, read_buf, read)| {
+            buf.extend_from_slice(&read_buf[..read]);
+
+            match parse_request(&buf, max_body_bytes) {
+                Ok(Parse::Complete(req)) => Either::A(future::ok(Loop::Break((conn, Ok(req))))),
+                Err(status) => Either::A(future::ok(Loop::Break((conn, Err(status))))),
+                // The connection was closed before the request was complete
+                Ok(Parse::Incomplete { .. }) if read == 0 => {
+                    Either::A(future::ok(Loop::Break((conn, Err(BAD_REQUEST)))))
+                }
+                // The client is waiting for permission to send the body
+                Ok(Parse::Incomplete {
+                    expect_continue: true,
+                }) if !continued => Either::B(
+                    io::write_all(conn, &b"HTTP/1.1 100 Continue\r\n\r\n"[..])
+                        .map(move |(conn, _)| Loop::Continue((conn, buf, true))),
+                ),
+                Ok(Parse::Incomplete { .. }) => {
+                    Either::A(future::ok(Loop::Continue((conn, buf, continued))))
+                }
+            }
+        })
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Parse {
+    Complete(Request),
+    Incomplete { expect_continue: bool },
+}
+
+/**
+Attempt to parse a complete request from the bytes read so far.
+
+If the request is invalid then the status to respond with is returned.
+*/
+fn parse_request(buf: &[u8], max_body_bytes: usize) -> Result<Parse, &'static str> {
+    let head_len = match find(buf, b"\r\n\r\n") {
+        Some(head_len) => head_len,
+        None if buf.len() > MAX_HEAD_BYTES => return Err(HEADERS_TOO_LARGE),
+        None => {
+            return Ok(Parse::Incomplete {
+                expect_continue: false,
+            })
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_len]);
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let (method, path) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => return Err(BAD_REQUEST),
+    };
+
+    let mut content_length = None;
+    let mut chunked = false;
+    let mut expect_continue = false;
+
+    for line in lines {
+        let mut header = line.splitn(2, ':');
+        let (name, value) = match (header.next(), header.next()) {
+            (Some(name), Some(value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            _ => return Err(BAD_REQUEST),
+        };
+
+        match &*name {
+            "content-length" => {
+                content_length = Some(value.parse::<usize>().map_err(|_| BAD_REQUEST)?)
+            }
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            "content-encoding" => match &*value.to_ascii_lowercase() {
+                "identity" | "gzip" | "deflate" => (),
+                _ => return Err(UNSUPPORTED_MEDIA_TYPE),
+            },
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            _ => (),
+        }
+    }
+
+    let body = &buf[head_len + 4..];
+
+    let body = if chunked {
+        parse_chunked(body, max_body_bytes)?
+    } else {
+        match content_length {
+            Some(len) if len > max_body_bytes => return Err(PAYLOAD_TOO_LARGE),
+            Some(len) if body.len() >= len => Some(body[..len].to_vec()),
+            Some(_) => None,
+            None => Some(Vec::new()),
+        }
+    };
+
+    Ok(match body {
+        Some(body) => Parse::Complete(Request { method, path, body }),
+        None => Parse::Incomplete { expect_continue },
+    })
+}
+
+/**
+Attempt to parse a complete body sent with `Transfer-Encoding: chunked`.
+*/
+fn parse_chunked(mut buf: &[u8], max_body_bytes: usize) -> Result<Option<Vec<u8>>, &'static str> {
+    let mut body = Vec::new();
+
+    loop {
+        let line_len = match find(buf, b"\r\n") {
+            Some(line_len) => line_len,
+            None => return Ok(None),
+        };
+
+        // Chunk extensions after a `;` are ignored
+        let size = String::from_utf8_lossy(&buf[..line_len]);
+        let size = size.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| BAD_REQUEST)?;
+
+        if size > max_body_bytes.saturating_sub(body.len()) {
+            return Err(PAYLOAD_TOO_LARGE);
+        }
+
+        buf = &buf[line_len + 2..];
+
+        // The last chunk is followed by optional trailers, which are ignored
+        if size == 0 {
+            let complete = buf.starts_with(b"\r\n") || find(buf, b"\r\n\r\n").is_some();
+
+            return Ok(if complete { Some(body) } else { None });
+        }
+
+        if buf.len() < size + 2 {
+            return Ok(None);
+        }
+
+        if &buf[size..size + 2] != b"\r\n" {
+            return Err(BAD_REQUEST);
+        }
+
+        body.extend_from_slice(&buf[..size]);
+        buf = &buf[size + 2..];
+    }
+}
+
+fn find(buf: &[u8], needle: &[u8]) -> Option<usize> {
+    buf.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete(method: &str, path: &str, body: &[u8]) -> Result<Parse, &'static str> {
+        Ok(Parse::Complete(Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            body: body.to_vec(),
+        }))
+    }
+
+    #[test]
+    fn parse_request_content_length() {
+        let req = b"POST /gelf HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello";
+
+        assert_eq!(complete("POST", "/gelf", b"hello"), parse_request(req, 1024));
+
+        assert_eq!(
+            Ok(Parse::Incomplete {
+                expect_continue: false
+            }),
+            parse_request(&req[..req.len() - 1], 1024)
+        );
+
+        assert_eq!(Err(PAYLOAD_TOO_LARGE), parse_request(req, 4));
+    }
+
+    #[test]
+    fn parse_request_chunked() {
+        let req = b"POST /gelf HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+
+        assert_eq!(complete("POST", "/gelf", b"hello world"), parse_request(req, 1024));
+
+        for len in 0..req.len() {
+            assert!(
+                matches!(parse_request(&req[..len], 1024), Ok(Parse::Incomplete { .. })),
+                "expected a partial request of {} bytes to be incomplete",
+                len
+            );
+        }
+
+        assert_eq!(Err(PAYLOAD_TOO_LARGE), parse_request(req, 10));
+    }
+
+    #[test]
+    fn parse_request_expect_continue() {
+        let req = b"POST /gelf HTTP/1.1\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n";
+
+        assert_eq!(
+            Ok(Parse::Incomplete {
+                expect_continue: true
+            }),
+            parse_request(req, 1024)
+        );
+    }
+
+    #[test]
+    fn respond_to_split_requests() {
+        use std::{
+            io::{Read, Write},
+            net, thread,
+        };
+
+        // Find a free port to bind the diagnostics server to
+        let addr = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let server = bind(&addr.to_string()).expect("failed to bind server");
+        thread::spawn(move || tokio::runtime::current_thread::block_on_all(server));
+
+        let mut conn = net::TcpStream::connect(addr).unwrap();
+        conn.set_nodelay(true).unwrap();
+
+        // Send the request line and headers in separate segments
+        for part in ["GET /he", "alth HTTP/1.1\r\n", "Host: localhost\r\n", "\r\n"] {
+            conn.write_all(part.as_bytes()).unwrap();
+            conn.flush().unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let mut res = String::new();
+        conn.read_to_string(&mut res).unwrap();
+
+        assert!(
+            res.starts_with("HTTP/1.1 200 OK") || res.starts_with("HTTP/1.1 503 Service Unavailable"),
+            "unexpected response `{}`",
+            res
+        );
+    }
+
+    #[test]
+    fn parse_request_invalid() {
+        let cases: Vec<(&[u8], &str)> = vec![
+            (b"POST\r\n\r\n", BAD_REQUEST),
+            (b"POST /gelf HTTP/1.1\r\nContent-Length: lots\r\n\r\n", BAD_REQUEST),
+            (b"POST /gelf HTTP/1.1\r\nContent-Encoding: br\r\n\r\n", UNSUPPORTED_MEDIA_TYPE),
+            (b"POST /gelf HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n", BAD_REQUEST),
+            (&[b'a'; MAX_HEAD_BYTES + 1], HEADERS_TOO_LARGE),
+        ];
+
+        for (req, expected) in cases {
+            assert_eq!(Err(expected), parse_request(req, 1024));
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::io;
+use std::{io, net::SocketAddr};
 
 /**
 A reader that may be a contiguous slice of bytes.
@@ -8,13 +8,30 @@ pub trait MemRead {
 
     fn bytes(&self) -> Option<&[u8]>;
     fn into_reader(self) -> io::Result<Self::Reader>;
+
+    /**
+    The address the bytes were received from, if it's known.
+    */
+    fn source(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /**
+    The address of the peer the bytes were received from over a connection,
+    like an HTTP request, if it's known.
+
+    Datagrams don't have a peer, even if their source is known.
+    */
+    fn peer(&self) -> Option<SocketAddr> {
+        None
+    }
 }
 
 impl<'a> MemRead for &'a [u8] {
     type Reader = io::Cursor<&'a [u8]>;
 
     fn bytes(&self) -> Option<&[u8]> {
-        Some(&self)
+        Some(self)
     }
 
     fn into_reader(self) -> io::Result<Self::Reader> {
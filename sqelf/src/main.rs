@@ -4,17 +4,27 @@ extern crate serde_derive;
 #[macro_use]
 pub mod error;
 
-mod diagnostics;
+#[macro_use]
+pub mod diagnostics;
+mod audit;
+mod http;
 pub mod io;
+pub mod output;
 pub mod process;
+pub mod queue;
+mod rate_limit;
 pub mod receive;
+pub mod replay;
+mod sample;
 pub mod server;
+#[cfg(target_os = "linux")]
+mod udp_drops;
 
 mod config;
 
 pub use self::config::Config;
 use self::{
-    diagnostics::emit_err,
+    diagnostics::{emit_debug, emit_err},
     error::{
         Error,
         err_msg,
@@ -24,7 +34,7 @@ use self::{
 use std::panic::catch_unwind;
 
 fn main() {
-    let run_server = catch_unwind(|| run())
+    let run_server = catch_unwind(run)
         .map_err(|panic| error::unwrap_panic(panic).into())
         .and_then(|inner| inner);
 
@@ -37,6 +47,14 @@ fn main() {
 fn run() -> Result<(), error::StdError> {
     let config = Config::from_env()?;
 
+    diagnostics::set_min_level(config.diagnostics.min_level);
+    diagnostics::set_instance_name(config.diagnostics.instance_name.clone());
+
+    emit_debug(
+        "Starting GELF input with {Config}",
+        serde_json::json!({ "Config": &config }),
+    );
+
     // The receiver for GELF messages
     let receive = {
         let mut receive = receive::build(config.receive);
@@ -46,15 +64,26 @@ fn run() -> Result<(), error::StdError> {
     // The processor for converting GELF into CLEF
     let process = {
         let process = process::build(config.process);
-        move |msg| process.read_as_clef(msg)
+        let mut output = output::build(config.output)?;
+        move |msg| process.read_as_clef(msg, &mut output)
     };
 
+    // Replay captured payloads instead of running the server
+    if config.replay.path.is_some() {
+        replay::replay(&config.replay, receive, process)?;
+
+        return Ok(());
+    }
+
     // The server that drives the receiver and processor
     let server = server::build(config.server, receive, process)?;
 
     // Run the server and wait for it to exit
     match tokio::runtime::current_thread::block_on_all(server) {
         Ok(()) | Err(server::Exit::Clean) => Ok(()),
+        Err(server::Exit::Abandoned) => {
+            Err(err_msg("Server shutdown abandoned unprocessed messages").into())
+        }
         _ => Err(err_msg("Server execution failed").into())
     }
 }
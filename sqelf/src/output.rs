@@ -0,0 +1,510 @@
+/*!
+Destinations for processed CLEF events.
+*/
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, LineWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::{NaiveDate, Utc};
+
+use serde::{
+    de::{self, Deserialize, Deserializer},
+    ser::{Serialize, Serializer},
+};
+
+use crate::error::{err_msg, Error};
+
+/**
+Output configuration.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /**
+    Where to write processed events.
+    */
+    pub kind: OutputKind,
+    /**
+    The path of the file to append events to when `kind` is `file`.
+    */
+    pub file_path: Option<PathBuf>,
+    /**
+    When to rotate the file when `kind` is `file`.
+
+    When a file is rotated, it's renamed with a `.1` suffix, any previously
+    rotated files have their suffix incremented, and a new file is started.
+    */
+    pub file_rotation: Rotation,
+    /**
+    The maximum number of rotated files to keep.
+
+    If this value is reached then the oldest rotated file is removed.
+    */
+    pub file_retained: usize,
+    /**
+    Whether to also write each event to `stdout` as pretty-printed JSON.
+
+    This is a debugging aid for seeing exactly what a sender's messages are
+    converted into. It can't be used alongside `stdout` output unless
+    `echo_only` is set, because the pretty-printed events would be mixed in
+    with the output.
+    */
+    pub echo: bool,
+    /**
+    Whether to only echo events, without writing them to the output.
+    */
+    pub echo_only: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            kind: OutputKind::default(),
+            file_path: None,
+            file_rotation: Rotation::default(),
+            file_retained: 7,
+            echo: false,
+            echo_only: false,
+        }
+    }
+}
+
+/**
+Where to write processed events.
+*/
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputKind {
+    /**
+    Write events as newline-delimited JSON to `stdout`.
+
+    This is how events are forwarded to Seq when running as a Seq app.
+    */
+    #[default]
+    Stdout,
+    /**
+    Append events as newline-delimited JSON to a file.
+    */
+    File,
+}
+
+impl FromStr for OutputKind {
+    type Err = ParseOutputKindError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_ascii_lowercase() {
+            "stdout" => Ok(OutputKind::Stdout),
+            "file" => Ok(OutputKind::File),
+            _ => Err(ParseOutputKindError(s.to_owned())),
+        }
+    }
+}
+
+/**
+An output kind that isn't supported.
+*/
+#[derive(Debug)]
+pub struct ParseOutputKindError(String);
+
+impl fmt::Display for ParseOutputKindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` isn't a supported output; expected `stdout` or `file`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseOutputKindError {}
+
+/**
+When to rotate an output file.
+
+When parsed, rotation is either `never`, `daily`, or a size like `size:100MB`.
+Sizes can use the suffixes `KB`, `MB`, or `GB`, which are powers of `1024`.
+*/
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /**
+    Never rotate the file.
+    */
+    #[default]
+    Never,
+    /**
+    Rotate the file once it would grow past the given size in bytes.
+    */
+    Size(u64),
+    /**
+    Rotate the file when the first event is written on a new UTC day.
+    */
+    Daily,
+}
+
+impl Rotation {
+    const SIZE_PREFIX: &'static str = "size:";
+}
+
+impl FromStr for Rotation {
+    type Err = ParseRotationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseRotationError(s.to_owned());
+
+        match &*s.trim().to_ascii_lowercase() {
+            "never" => Ok(Rotation::Never),
+            "daily" => Ok(Rotation::Daily),
+            rotation => {
+                let size = rotation.strip_prefix(Self::SIZE_PREFIX).ok_or_else(err)?.trim();
+
+                let (size, scale) = [("kb", 1024), ("mb", 1024 * 1024), ("gb", 1024 * 1024 * 1024), ("b", 1)]
+                    .iter()
+                    .find_map(|(suffix, scale)| size.strip_suffix(suffix).map(|size| (size, *scale)))
+                    .unwrap_or((size, 1));
+
+                let size: u64 = size.trim().parse().map_err(|_| err())?;
+
+                size.checked_mul(scale)
+                    .filter(|size| *size > 0)
+                    .map(Rotation::Size)
+                    .ok_or_else(err)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Rotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rotation::Never => f.write_str("never"),
+            Rotation::Daily => f.write_str("daily"),
+            Rotation::Size(size) => write!(f, "{}{}", Self::SIZE_PREFIX, size),
+        }
+    }
+}
+
+impl Serialize for Rotation {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Rotation {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let rotation = String::deserialize(deserializer)?;
+
+        rotation.parse().map_err(de::Error::custom)
+    }
+}
+
+/**
+A file rotation that isn't supported.
+*/
+#[derive(Debug)]
+pub struct ParseRotationError(String);
+
+impl fmt::Display for ParseRotationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` isn't a supported file rotation; expected `never`, `daily`, or a size like `size:100MB`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseRotationError {}
+
+/**
+A destination for processed CLEF events.
+*/
+pub trait OutputSink {
+    /**
+    Write a single CLEF event, serialized as JSON.
+    */
+    fn send(&mut self, event: &str) -> Result<(), Error>;
+}
+
+impl<T: OutputSink + ?Sized> OutputSink for &mut T {
+    fn send(&mut self, event: &str) -> Result<(), Error> {
+        (**self).send(event)
+    }
+}
+
+impl<T: OutputSink + ?Sized> OutputSink for Box<T> {
+    fn send(&mut self, event: &str) -> Result<(), Error> {
+        (**self).send(event)
+    }
+}
+
+/**
+Build a sink to write processed events to.
+*/
+pub fn build(config: Config) -> Result<Box<dyn OutputSink + Send + Sync>, Error> {
+    match (config.echo, config.echo_only) {
+        (true, true) => return Ok(Box::new(EchoSink(None))),
+        (true, false) if config.kind == OutputKind::Stdout => {
+            return Err(err_msg(
+                "echo can't be used alongside stdout output; use file output or set echo_only",
+            ))
+        }
+        (true, false) => {
+            let inner = build(Config {
+                echo: false,
+                ..config
+            })?;
+
+            return Ok(Box::new(EchoSink(Some(inner))));
+        }
+        _ => (),
+    }
+
+    match config.kind {
+        OutputKind::Stdout => Ok(Box::new(StdoutSink)),
+        OutputKind::File => {
+            let path = config
+                .file_path
+                .ok_or_else(|| err_msg("a file path is required for file output"))?;
+
+            Ok(Box::new(FileSink::open(
+                path,
+                config.file_rotation,
+                config.file_retained,
+            )?))
+        }
+    }
+}
+
+/**
+Write events as newline-delimited JSON to `stdout`.
+*/
+pub struct StdoutSink;
+
+impl OutputSink for StdoutSink {
+    fn send(&mut self, event: &str) -> Result<(), Error> {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+
+        writeln!(stdout, "{}", event)?;
+
+        Ok(())
+    }
+}
+
+/**
+Write events as pretty-printed JSON to `stdout`, optionally alongside another sink.
+*/
+pub struct EchoSink(Option<Box<dyn OutputSink + Send + Sync>>);
+
+impl OutputSink for EchoSink {
+    fn send(&mut self, event: &str) -> Result<(), Error> {
+        let pretty: serde_json::Value = serde_json::from_str(event)?;
+
+        {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+
+            serde_json::to_writer_pretty(&mut stdout, &pretty)?;
+            writeln!(stdout)?;
+        }
+
+        match self.0 {
+            Some(ref mut inner) => inner.send(event),
+            None => Ok(()),
+        }
+    }
+}
+
+/**
+Append events as newline-delimited JSON to a file, rotating it as configured.
+
+Events are flushed to the file as each one is written, so no events
+are lost if the process exits without dropping the sink.
+*/
+pub struct FileSink {
+    path: PathBuf,
+    rotation: Rotation,
+    retained: usize,
+    written: u64,
+    opened: NaiveDate,
+    file: LineWriter<File>,
+}
+
+impl FileSink {
+    pub fn open(path: impl Into<PathBuf>, rotation: Rotation, retained: usize) -> Result<Self, Error> {
+        let path = path.into();
+
+        let (file, written) = Self::open_file(&path)?;
+
+        Ok(FileSink {
+            path,
+            rotation,
+            retained,
+            written,
+            opened: today(),
+            file,
+        })
+    }
+
+    fn open_file(path: &Path) -> Result<(LineWriter<File>, u64), Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| err_msg(format_args!("failed to open output file `{}`: {}", path.display(), e)))?;
+
+        let written = file.metadata()?.len();
+
+        Ok((LineWriter::new(file), written))
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", n));
+
+        rotated.into()
+    }
+
+    fn should_rotate(&self, len: u64) -> bool {
+        if self.written == 0 {
+            return false;
+        }
+
+        match self.rotation {
+            Rotation::Never => false,
+            Rotation::Size(max_bytes) => self.written + len > max_bytes,
+            Rotation::Daily => today() != self.opened,
+        }
+    }
+
+    fn rotate(&mut self) -> Result<(), Error> {
+        self.file.flush()?;
+
+        if self.retained == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // Shift rotated files along, dropping the oldest
+            let _ = fs::remove_file(self.rotated_path(self.retained));
+
+            for n in (1..self.retained).rev() {
+                let from = self.rotated_path(n);
+
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(n + 1))?;
+                }
+            }
+
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        let (file, written) = Self::open_file(&self.path)?;
+        self.file = file;
+        self.written = written;
+        self.opened = today();
+
+        Ok(())
+    }
+}
+
+fn today() -> NaiveDate {
+    Utc::now().naive_utc().date()
+}
+
+impl OutputSink for FileSink {
+    fn send(&mut self, event: &str) -> Result<(), Error> {
+        let len = event.len() as u64 + 1;
+
+        if self.should_rotate(len) {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", event)?;
+        self.written += len;
+
+        Ok(())
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        let _ = self.file.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn parse_rotation() {
+        let cases = vec![
+            ("never", Some(Rotation::Never)),
+            ("daily", Some(Rotation::Daily)),
+            ("size:100", Some(Rotation::Size(100))),
+            ("size:100MB", Some(Rotation::Size(100 * 1024 * 1024))),
+            ("size: 2 kb", Some(Rotation::Size(2 * 1024))),
+            ("size:0", None),
+            ("size:lots", None),
+            ("hourly", None),
+        ];
+
+        for (rotation, expected) in cases {
+            assert_eq!(expected, rotation.parse().ok(), "unexpected parse of `{}`", rotation);
+        }
+    }
+
+    #[test]
+    fn echo_requires_a_separate_output() {
+        let config = |kind, echo_only| Config {
+            kind,
+            file_path: Some(env::temp_dir().join(format!("sqelf-echo-{}.json", std::process::id()))),
+            echo: true,
+            echo_only,
+            ..Default::default()
+        };
+
+        assert!(build(config(OutputKind::Stdout, false)).is_err());
+        assert!(build(config(OutputKind::Stdout, true)).is_ok());
+        assert!(build(config(OutputKind::File, false)).is_ok());
+
+        let _ = fs::remove_file(config(OutputKind::File, false).file_path.unwrap());
+    }
+
+    #[test]
+    fn file_sink_rotates_by_size() {
+        let dir = env::temp_dir().join(format!("sqelf-output-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("events.json");
+
+        let mut sink = FileSink::open(&path, Rotation::Size(16), 2).expect("failed to open sink");
+
+        for n in 1..=4 {
+            sink.send(&format!(r#"{{"@m":"{}"}}"#, n)).unwrap();
+        }
+
+        drop(sink);
+
+        // Only 2 rotated files are retained, so the first event is gone
+        assert_eq!("{\"@m\":\"2\"}\n", fs::read_to_string(dir.join("events.json.2")).unwrap());
+        assert_eq!("{\"@m\":\"3\"}\n", fs::read_to_string(dir.join("events.json.1")).unwrap());
+        assert_eq!("{\"@m\":\"4\"}\n", fs::read_to_string(&path).unwrap());
+        assert!(!dir.join("events.json.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
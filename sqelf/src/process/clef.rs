@@ -45,6 +45,11 @@ pub(super) struct Message<'a> {
     pub(super) additional: HashMap<Str<'a>, Value>,
 }
 
+/**
+How far into the future a timestamp can be before it's considered bogus.
+*/
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60 * 60 * 24);
+
 #[derive(Debug)]
 pub(super) struct Timestamp(SystemTime);
 
@@ -53,11 +58,15 @@ impl Timestamp {
         Timestamp(SystemTime::now())
     }
 
-    pub(super) fn from_float(ts: f64) -> Self {
-        // If the timestamp is before the epoch
-        // then just return the epoch
-        if ts.is_sign_negative() {
-            return Timestamp(SystemTime::UNIX_EPOCH);
+    /**
+    Convert a Unix timestamp in fractional seconds into a timestamp.
+
+    Timestamps are truncated to millisecond precision. If the timestamp is
+    before the epoch, or too far into the future, then `None` is returned.
+    */
+    pub(super) fn from_float(ts: f64) -> Option<Self> {
+        if !ts.is_finite() || ts.is_sign_negative() {
+            return None;
         }
 
         let secs = ts.trunc() as u64;
@@ -66,7 +75,13 @@ impl Timestamp {
             (nanos / 1_000_000) * 1_000_000
         };
 
-        Timestamp(SystemTime::UNIX_EPOCH + Duration::new(secs, nanos))
+        let ts = SystemTime::UNIX_EPOCH.checked_add(Duration::new(secs, nanos))?;
+
+        if ts > SystemTime::now() + MAX_CLOCK_SKEW {
+            return None;
+        }
+
+        Some(Timestamp(ts))
     }
 }
 
@@ -120,7 +135,7 @@ impl<'a> Message<'a> {
     }
 
     pub(super) fn maybe_from_json(json: &'a str) -> Option<Self> {
-        if json.chars().next() == Some('{') {
+        if json.starts_with('{') {
             serde_json::from_str(json).ok()
         } else {
             None
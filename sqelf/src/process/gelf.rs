@@ -1,3 +1,6 @@
+use std::convert::TryInto;
+
+use serde::de::{Deserialize, DeserializeOwned, Deserializer};
 use serde_json::Value;
 
 #[derive(Debug, Deserialize)]
@@ -7,11 +10,13 @@ pub(super) struct Message<TString, TMessage = TString> {
     pub(super) host: Option<TString>,
     pub(super) short_message: TMessage,
     pub(super) full_message: Option<TMessage>,
+    #[serde(default, deserialize_with = "lenient_timestamp")]
     pub(super) timestamp: Option<f64>,
     pub(super) level: Option<u8>,
 
     // Deprecated built-ins, still may be present
     pub(super) facility: Option<TMessage>,
+    #[serde(default, deserialize_with = "lenient_line")]
     pub(super) line: Option<u32>,
     pub(super) file: Option<TMessage>,
 
@@ -19,3 +24,78 @@ pub(super) struct Message<TString, TMessage = TString> {
     #[serde(flatten)]
     pub(super) additional: Option<Value>,
 }
+
+/**
+Read a GELF timestamp.
+
+Timestamps should be numbers, but some clients send them as strings.
+Any timestamp that can't be read as a number is treated as missing
+rather than failing the whole message.
+*/
+fn lenient_timestamp<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Number(ts) => ts.as_f64(),
+        Value::String(ts) => ts.trim().parse().ok(),
+        _ => None,
+    })
+}
+
+/**
+Read a GELF line number.
+
+Line numbers should be numbers, but some clients send them as strings.
+Any line number that can't be read as a number is treated as missing
+rather than failing the whole message.
+*/
+fn lenient_line<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Number(line) => line.as_u64().and_then(|line| line.try_into().ok()),
+        Value::String(line) => line.trim().parse().ok(),
+        _ => None,
+    })
+}
+
+/**
+Find the field that stopped a payload from being read as a GELF message.
+
+Built-in fields are read through a flattened map, so errors about them
+don't say which field they came from. This re-reads the payload to find
+the field, so it should only be called once reading has already failed.
+If the payload isn't a JSON object, or no field is at fault, then `None`
+is returned and the original error should be used.
+*/
+pub(super) fn explain_err(payload: &[u8]) -> Option<String> {
+    fn check<T: DeserializeOwned>(
+        message: &serde_json::Map<String, Value>,
+        field: &str,
+    ) -> Option<String> {
+        let value = message.get(field)?;
+
+        T::deserialize(value)
+            .err()
+            .map(|err| format!("`{}`: {}", field, err))
+    }
+
+    let message = match serde_json::from_slice(payload).ok()? {
+        Value::Object(message) => message,
+        _ => return None,
+    };
+
+    if !message.contains_key("short_message") {
+        return Some("missing field `short_message`".to_owned());
+    }
+
+    check::<String>(&message, "short_message")
+        .or_else(|| check::<Option<String>>(&message, "version"))
+        .or_else(|| check::<Option<String>>(&message, "host"))
+        .or_else(|| check::<Option<String>>(&message, "full_message"))
+        .or_else(|| check::<Option<u8>>(&message, "level"))
+        .or_else(|| check::<Option<String>>(&message, "facility"))
+        .or_else(|| check::<Option<String>>(&message, "file"))
+}
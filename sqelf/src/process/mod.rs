@@ -2,33 +2,254 @@ mod clef;
 mod gelf;
 mod str;
 
-use serde_json::Value;
+use serde_json::{json, Value};
 
 use self::str::{CachedString, Inlinable, Str};
 
 use crate::{
-    error::Error,
+    diagnostics::{emit, emit_debug, emit_warn},
+    error::{err_msg, Error, ErrorKind},
     io::MemRead,
+    output::OutputSink,
 };
 
-use std::collections::HashMap;
+use std::{
+    collections::{hash_map, HashMap},
+    fmt,
+    io::{self, Read},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
 
 /**
 Configuration for CELF formatting.
 */
-#[derive(Debug, Clone)]
-pub struct Config {}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /**
+    The format messages are expected to be in.
+    */
+    pub input_format: InputFormat,
+    /**
+    Whether or not to discard messages after converting them into CLEF.
+
+    Messages are still fully read and converted, so any errors are reported,
+    but nothing is written to the output. This is useful for checking that
+    senders are compatible without forwarding their events.
+    */
+    pub dry_run: bool,
+    /**
+    Fields to add to every event.
+
+    Values can refer to environment variables like `${REGION}`, which are
+    resolved at startup. If a message already has a field with the same
+    name then the message's value is kept.
+    */
+    pub enrich: HashMap<String, String>,
+    /**
+    Fields to add to events by their GELF `facility`.
+
+    Each facility maps to a set of fields, like `auth` to `Application = "AuthService"`.
+    Values are resolved the same way as `enrich`. If a message already has a field
+    with the same name then the message's value is kept. Fields for a facility are
+    added before `enrich`, so they take precedence over it. Events with a facility
+    that isn't mapped are left unchanged.
+    */
+    pub facilities: HashMap<String, HashMap<String, String>>,
+    /**
+    Properties to rename on every event, like `host` to `MachineName`.
+
+    Names refer to properties after additional fields have had their leading
+    underscore stripped, so `_app` and `app` both refer to the `app` property.
+    Names that begin with `@` are escaped by doubling the `@`, the same way
+    as additional fields, so they can't replace CLEF's built-in properties.
+    If an event already has a property with the new name then it's kept with
+    a `__` prefix. Renaming happens before enrichment fields are added.
+    */
+    pub rename: HashMap<String, String>,
+    /**
+    Whether to add the IP address a message was received from to its event
+    as `source_ip`.
+
+    If a message already has a `source_ip` field then the message's value is kept.
+    Messages received over Unix domain sockets don't have a source address.
+    */
+    pub attach_source_ip: bool,
+    /**
+    The maximum number of properties on an event.
+
+    This is checked after enrichment and renaming, and counts any
+    `_truncated_properties` or `_truncated_fields` tags. The GELF `host`,
+    `facility`, `file`, and `line` fields aren't counted, and are never
+    dropped. Events with more properties have the excess ones dropped.
+    */
+    pub max_properties: usize,
+    /**
+    The maximum depth of a property's value.
+
+    A value that's a scalar has a depth of `1`. Each level of nested objects
+    or arrays adds `1`. Values nested deeper are replaced with `null`.
+    */
+    pub max_json_depth: usize,
+    /**
+    The maximum length of a string value in bytes.
+
+    This applies to the message, exception, and every string property.
+    Longer values are cut to fit, ending with `…` if there's room for it,
+    and their names are listed on the event in `_truncated_fields`.
+    */
+    pub max_field_value_bytes: usize,
+}
 
 impl Default for Config {
     fn default() -> Self {
-        Config {}
+        Config {
+            input_format: InputFormat::default(),
+            dry_run: false,
+            enrich: HashMap::new(),
+            facilities: HashMap::new(),
+            rename: HashMap::new(),
+            attach_source_ip: false,
+            max_properties: 1024,
+            max_json_depth: 32,
+            max_field_value_bytes: 32 * 1024,
+        }
+    }
+}
+
+/**
+The format of received messages.
+*/
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputFormat {
+    /**
+    Messages are GELF, and are converted into CLEF.
+    */
+    #[default]
+    Gelf,
+    /**
+    Messages are already CLEF, and are forwarded with minimal changes.
+
+    Messages without a timestamp are given the time they were received,
+    and messages without a level are given the default level.
+    */
+    Clef,
+}
+
+impl FromStr for InputFormat {
+    type Err = ParseInputFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_ascii_lowercase() {
+            "gelf" => Ok(InputFormat::Gelf),
+            "clef" => Ok(InputFormat::Clef),
+            _ => Err(ParseInputFormatError(s.to_owned())),
+        }
+    }
+}
+
+/**
+An input format that isn't supported.
+*/
+#[derive(Debug)]
+pub struct ParseInputFormatError(String);
+
+impl fmt::Display for ParseInputFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` isn't a supported input format; expected `gelf` or `clef`",
+            self.0
+        )
     }
 }
 
+impl std::error::Error for ParseInputFormatError {}
+
+/**
+The CLEF level for each GELF level.
+
+GELF levels are the standard Syslog severities, where `0` is Emergency
+and `7` is Debug. The GELF level is the index into this table.
+*/
+pub const LEVELS: [&str; 8] = [
+    // Emergency
+    "Fatal",
+    // Alert
+    "Fatal",
+    // Critical
+    "Fatal",
+    // Error
+    "Error",
+    // Warning
+    "Warning",
+    // Notice
+    "Information",
+    // Informational
+    "Information",
+    // Debug
+    "Debug",
+];
+
+/**
+The GELF versions that messages are accepted for.
+*/
+pub const VERSIONS: [&str; 2] = ["1.0", "1.1"];
+
+/**
+The maximum number of characters of a rejected message to include in diagnostics.
+*/
+const MAX_REJECTED_PREVIEW_CHARS: usize = 256;
+
+/**
+The GELF level used when a message doesn't specify one.
+
+This is the Syslog Informational severity.
+*/
+const DEFAULT_LEVEL: u8 = 6;
+
+/**
+The property added to events that had properties dropped or truncated.
+*/
+const TRUNCATED_PROPERTIES: &str = "_truncated_properties";
+
+/**
+The property added to events with the names of string values that were cut short.
+*/
+const TRUNCATED_FIELDS: &str = "_truncated_fields";
+
+/**
+The marker at the end of string values that were cut short.
+*/
+const TRUNCATED_MARKER: &str = "…";
+
+/**
+The property added to events with the address their message was received from.
+*/
+const SOURCE_IP: &str = "source_ip";
+
+/**
+The properties mapped from built-in GELF fields.
+
+These aren't counted towards `max_properties`.
+*/
+const BUILT_IN_PROPERTIES: &[&str] = &["host", "facility", "file", "line"];
+
+/**
+The GELF host used when a message doesn't specify one.
+*/
+const DEFAULT_HOST: &str = "unknown";
+
 /**
 Build a CLEF processor to handle messages.
 */
 pub fn build(config: Config) -> Process {
+    if config.dry_run {
+        emit("Running in dry-run mode; processed messages will be discarded");
+    }
+
     Process::new(config)
 }
 
@@ -36,11 +257,69 @@ pub fn build(config: Config) -> Process {
 Process a raw message
 */
 #[derive(Clone)]
-pub struct Process {}
+pub struct Process {
+    config: Config,
+    enrich: Vec<(String, Value)>,
+    facilities: HashMap<String, Vec<(String, Value)>>,
+    rename: Vec<(String, String)>,
+    built_in: Vec<String>,
+}
 
 impl Process {
-    pub fn new(_: Config) -> Self {
-        Process {}
+    pub fn new(config: Config) -> Self {
+        fn interpolate(fields: &HashMap<String, String>) -> Vec<(String, Value)> {
+            fields
+                .iter()
+                .map(|(k, v)| (k.clone(), Value::String(interpolate_env(v))))
+                .collect()
+        }
+
+        let enrich = interpolate(&config.enrich);
+
+        let facilities = config
+            .facilities
+            .iter()
+            .map(|(facility, fields)| (facility.clone(), interpolate(fields)))
+            .collect();
+
+        // Renames are applied in a fixed order so collisions are resolved
+        // the same way for every event
+        let mut rename: Vec<_> = config
+            .rename
+            .iter()
+            .map(|(from, to)| {
+                let from = from.strip_prefix('_').unwrap_or(from).to_owned();
+
+                let to = if to.starts_with('@') {
+                    format!("@{}", to)
+                } else {
+                    to.clone()
+                };
+
+                (from, to)
+            })
+            .collect();
+        rename.sort();
+
+        // Built-in fields are still exempt from limits after they're renamed
+        let built_in = BUILT_IN_PROPERTIES
+            .iter()
+            .map(|name| {
+                rename
+                    .iter()
+                    .find(|(from, _)| from == name)
+                    .map(|(_, to)| to.clone())
+                    .unwrap_or_else(|| (*name).to_owned())
+            })
+            .collect();
+
+        Process {
+            config,
+            enrich,
+            facilities,
+            rename,
+            built_in,
+        }
     }
 
     fn with_clef(
@@ -48,22 +327,256 @@ impl Process {
         msg: impl MemRead,
         with: impl FnOnce(clef::Message) -> Result<(), Error>,
     ) -> Result<(), Error> {
+        let peer = msg.peer();
+
+        let source_ip = if self.config.attach_source_ip {
+            msg.source().map(|source| source.ip())
+        } else {
+            None
+        };
+
+        let with = self.enriched(source_ip, with);
+
+        if self.config.input_format == InputFormat::Clef {
+            return Self::with_clef_passthrough(msg, with);
+        }
+
         if let Some(bytes) = msg.bytes() {
-            let value: gelf::Message<Str> = serde_json::from_slice(bytes)?;
+            let bytes = trim_bom(bytes);
+
+            let value: gelf::Message<Str> =
+                serde_json::from_slice(bytes).map_err(|err| gelf_parse_err(bytes, err))?;
 
-            with(value.to_clef())
+            value.check_version(|| String::from_utf8_lossy(bytes).into_owned())?;
+
+            with(value.to_clef(peer))
         } else {
+            let reader = msg
+                .into_reader()
+                .and_then(skip_bom)
+                .map_err(|e| Error::from(e).with_kind(ErrorKind::Decompress))?;
+
             let value: gelf::Message<Inlinable<CachedString>, String> =
-                serde_json::from_reader(msg.into_reader()?)?;
+                serde_json::from_reader(reader).map_err(parse_err)?;
+
+            // The original payload has been consumed by the reader
+            // so the short message is reported instead
+            value.check_version(|| value.short_message.clone())?;
+
+            with(value.to_clef(peer))
+        }
+    }
+
+    fn with_clef_passthrough(
+        msg: impl MemRead,
+        with: impl FnOnce(clef::Message) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let read;
+        let bytes = match msg.bytes() {
+            Some(bytes) => bytes,
+            None => {
+                let mut buf = Vec::new();
+                msg.into_reader()
+                    .and_then(|mut reader| reader.read_to_end(&mut buf))
+                    .map_err(|e| Error::from(e).with_kind(ErrorKind::Decompress))?;
+
+                read = buf;
+                &read
+            }
+        };
+
+        let bytes = trim_bom(bytes);
+
+        let mut clef: clef::Message = serde_json::from_slice(bytes).map_err(parse_err)?;
+
+        if clef.message.is_none() && clef.message_template.is_none() {
+            return Err(err_msg("CLEF message is missing both `@m` and `@mt`").with_kind(ErrorKind::Parse));
+        }
+
+        if clef.timestamp.is_none() {
+            increment!(timestamp_defaulted);
+
+            clef.timestamp = Some(clef::Timestamp::now());
+        }
+
+        if clef.level.is_none() {
+            clef.level = Some(Str::Borrowed(LEVELS[DEFAULT_LEVEL as usize]));
+        }
+
+        with(clef)
+    }
+
+    /**
+    Add the configured enrichment fields to a message and limit its
+    properties before handling it.
+
+    Fields already on the message take precedence over enrichment fields,
+    and fields for the message's facility take precedence over the rest.
+    */
+    fn enriched<'b>(
+        &'b self,
+        source_ip: Option<IpAddr>,
+        with: impl FnOnce(clef::Message) -> Result<(), Error> + 'b,
+    ) -> impl FnOnce(clef::Message) -> Result<(), Error> + 'b {
+        move |mut clef| {
+            if let Some(source_ip) = source_ip {
+                clef.additional
+                    .entry(Str::Borrowed(SOURCE_IP))
+                    .or_insert_with(|| Value::String(source_ip.to_string()));
+            }
+
+            let facility = clef
+                .additional
+                .get(&Str::Borrowed("facility"))
+                .and_then(Value::as_str)
+                .and_then(|facility| self.facilities.get(facility));
+
+            self.rename(&mut clef);
+
+            for (k, v) in facility.into_iter().flatten().chain(&self.enrich) {
+                clef.additional
+                    .entry(Str::Owned(k.clone()))
+                    .or_insert_with(|| v.clone());
+            }
+
+            self.limit(&mut clef);
+
+            with(clef)
+        }
+    }
+
+    /**
+    Rename properties on a message.
+
+    Every property is removed before any are inserted, so renames
+    can't chain into each other.
+    */
+    fn rename(&self, clef: &mut clef::Message) {
+        if self.rename.is_empty() {
+            return;
+        }
+
+        let renamed: Vec<_> = self
+            .rename
+            .iter()
+            .filter_map(|(from, to)| {
+                clef.additional
+                    .remove(&Str::Owned(from.clone()))
+                    .map(|value| (to, value))
+            })
+            .collect();
+
+        for (to, value) in renamed {
+            override_value(&mut clef.additional, Str::Owned(to.clone()), value);
+        }
+    }
+
+    /**
+    Enforce the maximum number of properties, their depth, and the length
+    of string values.
+
+    String values that are too long are cut short, and their names are
+    listed in `_truncated_fields`. Values nested too deeply are replaced
+    with `null`. Properties past the limit are dropped, in name order so
+    the same properties are kept for similar messages, leaving room for
+    the tags. If any values were replaced or properties dropped then the
+    message is tagged with `_truncated_properties` so it's clear it isn't
+    complete.
+    */
+    fn limit(&self, clef: &mut clef::Message) {
+        let max = self.config.max_field_value_bytes;
+        let mut truncated_fields = Vec::new();
+
+        for (name, value) in [
+            ("@m", &mut clef.message),
+            ("@mt", &mut clef.message_template),
+            ("@x", &mut clef.exception),
+        ] {
+            if let Some(value) = value {
+                if let Some(short) = truncate_len(value.as_ref(), max) {
+                    *value = Str::Owned(short);
+                    truncated_fields.push(name.to_owned());
+                }
+            }
+        }
 
-            with(value.to_clef())
+        for (name, value) in &mut clef.additional {
+            if let Value::String(value) = value {
+                if let Some(short) = truncate_len(value, max) {
+                    *value = short;
+                    truncated_fields.push(name.as_ref().to_owned());
+                }
+            }
+        }
+
+        let mut truncated = false;
+
+        for value in clef.additional.values_mut() {
+            truncated |= truncate_depth(value, self.config.max_json_depth);
+        }
+
+        let mut names: Vec<_> = clef
+            .additional
+            .keys()
+            .map(|k| k.as_ref())
+            .filter(|k| *k != TRUNCATED_PROPERTIES && *k != TRUNCATED_FIELDS)
+            .filter(|k| !self.built_in.iter().any(|built_in| built_in == k))
+            .map(ToOwned::to_owned)
+            .collect();
+
+        // The `_truncated_fields` tag is only added if a truncated field isn't dropped
+        let fields_tag = |dropped: &[String]| {
+            truncated_fields.iter().any(|name| !dropped.contains(name)) as usize
+        };
+
+        if names.len() + truncated as usize + fields_tag(&[]) > self.config.max_properties {
+            names.sort_unstable();
+
+            // Dropping properties needs a `_truncated_properties` tag,
+            // so always make room for it. Only make room for the
+            // `_truncated_fields` tag if a truncated field is kept
+            let mut keep = self.config.max_properties.saturating_sub(1);
+            if fields_tag(names.get(keep..).unwrap_or(&[])) > 0 {
+                keep = keep.saturating_sub(1);
+            }
+
+            for name in names.into_iter().skip(keep) {
+                clef.additional.remove(&Str::Owned(name));
+            }
+
+            truncated = true;
+        }
+
+        if truncated {
+            clef.additional
+                .insert(Str::Borrowed(TRUNCATED_PROPERTIES), Value::Bool(true));
+        }
+
+        // Only list fields that weren't dropped
+        truncated_fields.retain(|name| {
+            name.starts_with('@') || clef.additional.contains_key(&Str::Owned(name.clone()))
+        });
+
+        if truncated || !truncated_fields.is_empty() {
+            increment!(process_truncated);
+        }
+
+        if !truncated_fields.is_empty() {
+            truncated_fields.sort_unstable();
+
+            clef.additional.insert(
+                Str::Borrowed(TRUNCATED_FIELDS),
+                Value::Array(truncated_fields.into_iter().map(Value::String).collect()),
+            );
         }
     }
 
-    pub fn read_as_clef(&self, msg: impl MemRead) -> Result<(), Error> {
+    pub fn read_as_clef(&self, msg: impl MemRead, mut output: impl OutputSink) -> Result<(), Error> {
         self.with_clef(msg, |clef| {
             if let Ok(clef) = serde_json::to_string(&clef) {
-                println!("{}", clef);
+                if !self.config.dry_run {
+                    output.send(&clef)?;
+                }
             }
 
             Ok(())
@@ -79,6 +592,9 @@ where
     /**
     Covert a GELF message into CLEF.
 
+    The GELF short message is used as the CLEF message. If the GELF full message
+    is present and differs from the short message then it's used as the CLEF exception.
+
     The contents of the GELF message is inspected and deserialized as CLEF-encoded
     JSON if possible. In this case, timestamp, message, and level information from
     the embedded CLEF is given precedence over the outer GELF envelope.
@@ -92,8 +608,15 @@ where
 
     If fields conflict, then the lower-priority field is included with a
     double-underscore-prefixed name, e.g.: "__host".
+
+    The deprecated GELF `facility`, `file`, and `line` fields are kept as
+    properties with the same names when they're present.
+
+    If the GELF `host` is missing then the IP address of the connection's
+    `peer` is used. Messages received in datagrams don't have a peer, so
+    their host is reported as `unknown`.
     */
-    fn to_clef(&self) -> clef::Message {
+    fn to_clef(&self, peer: Option<SocketAddr>) -> clef::Message<'_> {
         #![deny(unused_variables)]
 
         let gelf::Message {
@@ -113,25 +636,25 @@ where
             .unwrap_or_else(|| clef::Message::from_message(short_message.as_ref()));
 
         // Set the log level; these are the standard Syslog levels
+        // Any levels past the end of the table are treated as debug
         if clef.level.is_none() {
-            clef.level = Some(match level.unwrap_or(6) {
-                0 => Str::Borrowed("emerg"),
-                1 => Str::Borrowed("alert"),
-                2 => Str::Borrowed("crit"),
-                3 => Str::Borrowed("err"),
-                4 => Str::Borrowed("warning"),
-                5 => Str::Borrowed("notice"),
-                6 => Str::Borrowed("info"),
-                7 => Str::Borrowed("debug"),
-                _ => Str::Borrowed("debug"),
-            })
+            let level = level.unwrap_or(DEFAULT_LEVEL) as usize;
+
+            clef.level = Some(Str::Borrowed(
+                LEVELS.get(level).unwrap_or(&LEVELS[LEVELS.len() - 1]),
+            ));
         }
 
         // Set the timestamp
         if clef.timestamp.is_none() {
+            // If the timestamp is missing or bogus then use the time it was received
             clef.timestamp = timestamp
-                .map(clef::Timestamp::from_float)
-                .or_else(|| Some(clef::Timestamp::now()));
+                .and_then(clef::Timestamp::from_float)
+                .or_else(|| {
+                    increment!(timestamp_defaulted);
+
+                    Some(clef::Timestamp::now())
+                });
         }
 
         // Set the exception, giving priority to the embedded CLEF exception.
@@ -150,56 +673,102 @@ where
         // because we trust the configuration of the logger ahead of any one event.
         if let Some(additional) = self.additional() {
             for (k, v) in additional {
-                Self::override_value(&mut clef.additional, k, v.clone());
+                override_value(&mut clef.additional, k, v.clone());
             }
         }
 
         // Set GELF built-in properties; we also trust these ahead of any one event's properties.
-        if let Some(host) = host {
-            Self::override_value(
-                &mut clef.additional,
-                "host",
-                host.as_ref().to_string().into(),
-            );
-        }
+        // GELF requires a host, so if it's missing then it's reported as the
+        // connection's peer, or as unknown if there's no connection
+        let host = match host {
+            Some(host) => host.as_ref().to_owned(),
+            None => {
+                increment!(host_defaulted);
+
+                peer.map(|peer| peer.ip().to_string())
+                    .unwrap_or_else(|| DEFAULT_HOST.to_owned())
+            }
+        };
+
+        override_value(&mut clef.additional, Str::Borrowed("host"), host.into());
 
         if let Some(facility) = facility {
-            Self::override_value(
+            override_value(
                 &mut clef.additional,
-                "facility",
+                Str::Borrowed("facility"),
                 facility.as_ref().to_string().into(),
             );
         }
 
         if let Some(file) = file {
-            Self::override_value(
+            override_value(
                 &mut clef.additional,
-                "file",
+                Str::Borrowed("file"),
                 file.as_ref().to_string().into(),
             );
         }
 
         if let Some(line) = line {
-            Self::override_value(&mut clef.additional, "line", (*line).into());
+            override_value(&mut clef.additional, Str::Borrowed("line"), (*line).into());
         }
 
         clef
     }
 
-    fn override_value<'a>(
-        fields: &mut HashMap<Str<'a>, Value>,
-        name: &'a (impl AsRef<str> + ?Sized),
-        value: Value,
-    ) {
-        if let Some(old) = fields.insert(Str::Borrowed(name.as_ref()), value) {
-            fields.insert(Str::Owned(format!("__{}", name.as_ref())), old);
+    /**
+    Check that the GELF message has a recognized version.
+
+    If the version isn't recognized then a preview of the payload is emitted
+    to help track down the sender.
+    */
+    fn check_version(&self, payload: impl FnOnce() -> String) -> Result<(), Error> {
+        let version = self.version.as_ref().map(AsRef::as_ref);
+
+        if version.map(|version| VERSIONS.contains(&version)).unwrap_or(false) {
+            return Ok(());
         }
+
+        increment!(receive_invalid_version);
+
+        let payload: String = payload().chars().take(MAX_REJECTED_PREVIEW_CHARS).collect();
+        emit_debug(
+            "Rejected a GELF message with version {Version}",
+            json!({
+                "Version": version,
+                "Payload": payload,
+            }),
+        );
+
+        let err = match version {
+            Some(version) => err_msg(format_args!("unrecognized GELF version `{}`", version)),
+            None => err_msg("missing GELF version"),
+        };
+
+        Err(err.with_kind(ErrorKind::Parse))
     }
 
-    fn additional(&self) -> Option<impl IntoIterator<Item = (&str, &Value)>> {
+    /**
+    Get the additional fields on the GELF message.
+
+    Values keep their JSON types, so numbers and booleans can be queried as such.
+    GELF only allows strings and numbers, but objects and arrays are kept structured
+    rather than stringified, subject to the configured `max_json_depth`.
+
+    Additional fields are prefixed with an underscore, which is stripped.
+    Property names that begin with `@` are reserved by CLEF. Any additional
+    fields that begin with `@` after stripping the underscore are escaped
+    by doubling the `@`, so `_@t` becomes `@@t`.
+    */
+    fn additional(&self) -> Option<impl IntoIterator<Item = (Str<'_>, &Value)>> {
         match self.additional {
             Some(Value::Object(ref additional)) => Some(additional.iter().map(|(k, v)| {
-                let k = if k.starts_with('_') { &k[1..] } else { &k };
+                let k = k.strip_prefix('_').unwrap_or(k);
+
+                let k = if k.starts_with('@') {
+                    Str::Owned(format!("@{}", k))
+                } else {
+                    Str::Borrowed(k)
+                };
 
                 (k, v)
             })),
@@ -208,54 +777,1090 @@ where
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/**
+Insert a property, keeping any existing value under a `__` prefixed name.
+*/
+fn override_value<'a>(fields: &mut HashMap<Str<'a>, Value>, name: Str<'a>, value: Value) {
+    let displaced = match fields.entry(name) {
+        hash_map::Entry::Occupied(mut entry) => {
+            let name = format!("__{}", entry.key().as_ref());
+            let old = entry.insert(value);
 
-    use serde_json::json;
+            Some((name, old))
+        }
+        hash_map::Entry::Vacant(entry) => {
+            entry.insert(value);
 
-    #[test]
-    fn from_gelf_msg() {
-        let gelf = json!({
-            "version": "1.1",
-            "host": "example.org",
-            "short_message": "A short message that helps you identify what is going on",
-            "full_message": "Backtrace here",
-            "timestamp": 1385053862.3072,
-            "level": 1,
-            "_user_id": 9001,
-            "_some_info": "foo",
-            "_some_env_var": "bar"
-        });
+            None
+        }
+    };
 
-        let process = Process::new(Default::default());
+    if let Some((name, old)) = displaced {
+        fields.insert(Str::Owned(name), old);
+    }
+}
 
-        process
-            .with_clef(gelf.to_string().as_bytes(), |clef| {
-                if let Str::Owned(_) = clef.message.as_ref().expect("missing message") {
-                    panic!("expected a borrowed message string");
-                }
+/**
+The UTF-8 byte order mark.
 
-                let expected = json!({
-                    "@t": "2013-11-21T17:11:02.307000000Z",
-                    "@l": "alert",
-                    "@m": "A short message that helps you identify what is going on",
-                    "@x": "Backtrace here",
-                    "some_env_var": "bar",
-                    "some_info": "foo",
-                    "user_id": 9001,
-                    "host": "example.org",
-                });
+Some senders prepend this to their JSON payloads, but it isn't valid JSON.
+Leading whitespace is already ignored when parsing JSON.
+*/
+const BOM: &[u8] = &[0xef, 0xbb, 0xbf];
 
-                let clef = serde_json::to_value(&clef).expect("failed to read clef");
+fn trim_bom(bytes: &[u8]) -> &[u8] {
+    bytes.strip_prefix(BOM).unwrap_or(bytes)
+}
 
-                assert_eq!(expected, clef);
+fn skip_bom<R: Read>(mut reader: R) -> io::Result<io::Chain<io::Cursor<Vec<u8>>, R>> {
+    let mut prefix = Vec::with_capacity(BOM.len());
+    (&mut reader).take(BOM.len() as u64).read_to_end(&mut prefix)?;
 
-                Ok(())
-            })
+    if prefix == BOM {
+        prefix.clear();
+    }
+
+    Ok(io::Cursor::new(prefix).chain(reader))
+}
+
+/**
+Replace any values nested deeper than `depth` with `null`.
+
+Returns `true` if any values were replaced.
+*/
+fn truncate_depth(value: &mut Value, depth: usize) -> bool {
+    match value {
+        Value::Object(_) | Value::Array(_) if depth <= 1 => {
+            *value = Value::Null;
+
+            true
+        }
+        Value::Object(object) => object
+            .values_mut()
+            .fold(false, |truncated, child| truncate_depth(child, depth - 1) | truncated),
+        Value::Array(array) => array
+            .iter_mut()
+            .fold(false, |truncated, child| truncate_depth(child, depth - 1) | truncated),
+        _ => false,
+    }
+}
+
+/**
+Cut a string down to at most `max` bytes, ending with a marker.
+
+Strings are only cut on character boundaries. If `max` is too small to
+fit the marker then the string is cut without one. Returns `None` if the
+string is already short enough.
+*/
+fn truncate_len(value: &str, max: usize) -> Option<String> {
+    if value.len() <= max {
+        return None;
+    }
+
+    // If there isn't room for the marker then the string is just cut
+    let marker = if max >= TRUNCATED_MARKER.len() {
+        TRUNCATED_MARKER
+    } else {
+        ""
+    };
+
+    let mut end = max - marker.len();
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    Some(format!("{}{}", &value[..end], marker))
+}
+
+/**
+Replace references to environment variables like `${NAME}` with their values.
+
+Variables that aren't set are replaced with an empty string.
+*/
+fn interpolate_env(value: &str) -> String {
+    let mut interpolated = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        interpolated.push_str(&rest[..start]);
+
+        let name = &rest[start + 2..end];
+        match std::env::var(name) {
+            Ok(var) => interpolated.push_str(&var),
+            Err(_) => emit_warn(
+                "Environment variable {Name} used in an enrichment field isn't set",
+                json!({ "Name": name }),
+            ),
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    interpolated.push_str(rest);
+    interpolated
+}
+
+/**
+Categorize an error reading a GELF message.
+
+Messages are decompressed as they're read, so any IO errors
+are failures to decompress.
+*/
+fn parse_err(err: serde_json::Error) -> Error {
+    let kind = if err.is_io() {
+        ErrorKind::Decompress
+    } else {
+        ErrorKind::Parse
+    };
+
+    Error::from(err).with_kind(kind)
+}
+
+/**
+Categorize an error reading a GELF message from a complete payload.
+
+If the failure can be traced to a field then the error names it.
+The rejected payload is previewed in a diagnostic event.
+*/
+fn gelf_parse_err(payload: &[u8], err: serde_json::Error) -> Error {
+    if err.is_io() {
+        return parse_err(err);
+    }
+
+    let err = match gelf::explain_err(payload) {
+        Some(explained) => err_msg(explained),
+        None => Error::from(err),
+    }
+    .with_kind(ErrorKind::Parse);
+
+    let payload: String = String::from_utf8_lossy(payload)
+        .chars()
+        .take(MAX_REJECTED_PREVIEW_CHARS)
+        .collect();
+    emit_debug(
+        "Rejected an unreadable GELF message: {Error}",
+        json!({
+            "Error": err.to_string(),
+            "Payload": payload,
+        }),
+    );
+
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::diagnostics::METRICS;
+
+    use serde_json::json;
+
+    #[test]
+    fn from_gelf_msg() {
+        let gelf = json!({
+            "version": "1.1",
+            "host": "example.org",
+            "short_message": "A short message that helps you identify what is going on",
+            "full_message": "Backtrace here",
+            "timestamp": 1385053862.3072,
+            "level": 1,
+            "_user_id": 9001,
+            "_some_info": "foo",
+            "_some_env_var": "bar"
+        });
+
+        let process = Process::new(Default::default());
+
+        process
+            .with_clef(gelf.to_string().as_bytes(), |clef| {
+                if let Str::Owned(_) = clef.message.as_ref().expect("missing message") {
+                    panic!("expected a borrowed message string");
+                }
+
+                let expected = json!({
+                    "@t": "2013-11-21T17:11:02.307000000Z",
+                    "@l": "Fatal",
+                    "@m": "A short message that helps you identify what is going on",
+                    "@x": "Backtrace here",
+                    "some_env_var": "bar",
+                    "some_info": "foo",
+                    "user_id": 9001,
+                    "host": "example.org",
+                });
+
+                let clef = serde_json::to_value(&clef).expect("failed to read clef");
+
+                assert_eq!(expected, clef);
+
+                Ok(())
+            })
+            .expect("failed to read gelf event");
+    }
+
+    #[test]
+    fn from_gelf_additional_types() {
+        let gelf = json!({
+            "version": "1.1",
+            "host": "example.org",
+            "short_message": "A short message",
+            "timestamp": 1385053862.3072,
+            "_bytes": 1024,
+            "_elapsed": 0.5,
+            "_success": true,
+            "_user": null,
+            "_request": { "path": "/", "headers": ["accept"] }
+        });
+
+        let process = Process::new(Default::default());
+
+        process
+            .with_clef(gelf.to_string().as_bytes(), |clef| {
+                let expected = json!({
+                    "@t": "2013-11-21T17:11:02.307000000Z",
+                    "@l": "Information",
+                    "@m": "A short message",
+                    "bytes": 1024,
+                    "elapsed": 0.5,
+                    "success": true,
+                    "user": null,
+                    "request": { "path": "/", "headers": ["accept"] },
+                    "host": "example.org",
+                });
+
+                let clef = serde_json::to_value(&clef).expect("failed to read clef");
+
+                assert_eq!(expected, clef);
+
+                Ok(())
+            })
+            .expect("failed to read gelf event");
+    }
+
+    #[test]
+    fn from_gelf_additional_reserved_names() {
+        let gelf = json!({
+            "version": "1.1",
+            "host": "example.org",
+            "short_message": "A short message",
+            "timestamp": 1385053862.3072,
+            "_@t": "not a timestamp",
+            "_@m": "not a message",
+            "_host": "payload.example.org",
+            "_version": "not a version"
+        });
+
+        let process = Process::new(Default::default());
+
+        process
+            .with_clef(gelf.to_string().as_bytes(), |clef| {
+                let expected = json!({
+                    "@t": "2013-11-21T17:11:02.307000000Z",
+                    "@l": "Information",
+                    "@m": "A short message",
+                    "@@t": "not a timestamp",
+                    "@@m": "not a message",
+                    "host": "example.org",
+                    "__host": "payload.example.org",
+                    "version": "not a version"
+                });
+
+                let clef = serde_json::to_value(&clef).expect("failed to read clef");
+
+                assert_eq!(expected, clef);
+
+                Ok(())
+            })
             .expect("failed to read gelf event");
     }
 
+    #[test]
+    fn from_gelf_short_full_message() {
+        let cases = vec![
+            (None, None),
+            (Some("A short message"), None),
+            (Some("A full message"), Some("A full message")),
+        ];
+
+        let process = Process::new(Default::default());
+
+        for (full_message, expected) in cases {
+            let mut gelf = json!({
+                "version": "1.1",
+                "host": "example.org",
+                "short_message": "A short message",
+            });
+
+            if let Some(full_message) = full_message {
+                gelf["full_message"] = full_message.into();
+            }
+
+            process
+                .with_clef(gelf.to_string().as_bytes(), |clef| {
+                    assert_eq!(
+                        Some("A short message"),
+                        clef.message.as_ref().map(AsRef::as_ref)
+                    );
+                    assert_eq!(
+                        expected,
+                        clef.exception.as_ref().map(AsRef::as_ref),
+                        "unexpected CLEF exception for GELF full message {:?}",
+                        full_message
+                    );
+
+                    Ok(())
+                })
+                .expect("failed to read gelf event");
+        }
+    }
+
+    #[test]
+    fn from_gelf_missing_short_message_fails() {
+        let gelf = json!({
+            "version": "1.1",
+            "host": "example.org",
+            "full_message": "A full message",
+        });
+
+        let process = Process::new(Default::default());
+
+        let err = process
+            .with_clef(gelf.to_string().as_bytes(), |_| {
+                panic!("expected a missing short message to fail")
+            })
+            .expect_err("expected a missing short message to fail");
+
+        assert!(err.to_string().contains("short_message"));
+        assert_eq!(ErrorKind::Parse, err.kind());
+    }
+
+    #[test]
+    fn from_gelf_invalid_field() {
+        let cases = vec![
+            (json!({ "version": "1.1", "short_message": "A", "level": "high" }), "`level`: invalid type: string \"high\""),
+            (json!({ "version": "1.1", "short_message": 42 }), "`short_message`: invalid type: integer `42`"),
+            (json!({ "version": "1.1", "short_message": "A", "host": ["a"] }), "`host`: invalid type: sequence"),
+            (json!({ "version": "1.1" }), "missing field `short_message`"),
+        ];
+
+        let process = Process::new(Default::default());
+
+        for (gelf, expected) in cases {
+            let err = process
+                .with_clef(gelf.to_string().as_bytes(), |_| {
+                    panic!("expected an invalid field to fail")
+                })
+                .expect_err("expected an invalid field to fail");
+
+            assert!(err.to_string().starts_with(expected), "unexpected error `{}`", err);
+            assert_eq!(ErrorKind::Parse, err.kind());
+        }
+    }
+
+    #[test]
+    fn from_gelf_timestamp() {
+        let cases = vec![
+            (json!(1385053862.3072), Some("2013-11-21T17:11:02.307000000Z")),
+            (json!(1385053862), Some("2013-11-21T17:11:02Z")),
+            (json!("1385053862.3072"), Some("2013-11-21T17:11:02.307000000Z")),
+            (json!(-1385053862.3072), None),
+            (json!(1e12), None),
+            (json!("not a timestamp"), None),
+            (json!(null), None),
+        ];
+
+        let process = Process::new(Default::default());
+
+        for (timestamp, expected) in cases {
+            let gelf = json!({
+                "version": "1.1",
+                "host": "example.org",
+                "short_message": "A short message",
+                "timestamp": timestamp,
+            });
+
+            let before = METRICS.timestamp_defaulted.get();
+
+            process
+                .with_clef(gelf.to_string().as_bytes(), |clef| {
+                    let clef = serde_json::to_value(&clef).expect("failed to read clef");
+
+                    match expected {
+                        Some(expected) => assert_eq!(
+                            expected, clef["@t"],
+                            "unexpected CLEF timestamp for GELF timestamp {}",
+                            timestamp
+                        ),
+                        None => {
+                            assert!(clef["@t"].is_string());
+                            assert!(METRICS.timestamp_defaulted.get() > before);
+                        }
+                    }
+
+                    Ok(())
+                })
+                .expect("failed to read gelf event");
+        }
+    }
+
+    #[test]
+    fn limit_properties() {
+        let process = Process::new(Config {
+            max_properties: 3,
+            max_json_depth: 2,
+            enrich: vec![("app".to_owned(), "Billing".to_owned())].into_iter().collect(),
+            ..Default::default()
+        });
+
+        let mut gelf = json!({
+            "version": "1.1",
+            "host": "example.org",
+            "short_message": "A short message",
+            "facility": "auth",
+            "file": "main.rs",
+            "line": 42,
+        });
+
+        for n in 0..10 {
+            gelf[format!("_field_{}", n)] = json!(n);
+        }
+
+        let before = METRICS.process_truncated.get();
+
+        process
+            .with_clef(gelf.to_string().as_bytes(), |clef| {
+                // Built-in fields aren't counted, and the enrichment field
+                // and the tag count towards the limit
+                let mut names: Vec<_> = clef.additional.keys().map(|k| k.as_ref().to_owned()).collect();
+                names.sort();
+
+                assert_eq!(
+                    vec!["_truncated_properties", "app", "facility", "field_0", "file", "host", "line"],
+                    names
+                );
+
+                Ok(())
+            })
+            .expect("failed to read gelf event");
+
+        assert!(METRICS.process_truncated.get() > before);
+
+        let process = Process::new(Config {
+            max_properties: 3,
+            max_json_depth: 2,
+            ..Default::default()
+        });
+
+        let gelf = json!({
+            "version": "1.1",
+            "host": "example.org",
+            "short_message": "A short message",
+            "_shallow": [1, 2],
+            "_deep": { "a": { "b": 1 } },
+        });
+
+        process
+            .with_clef(gelf.to_string().as_bytes(), |clef| {
+                let clef = serde_json::to_value(&clef).expect("failed to read clef");
+
+                assert_eq!(json!([1, 2]), clef["shallow"]);
+                assert_eq!(json!({ "a": null }), clef["deep"]);
+                assert_eq!(json!(true), clef["_truncated_properties"]);
+
+                Ok(())
+            })
+            .expect("failed to read gelf event");
+    }
+
+    #[test]
+    fn limit_properties_with_dropped_truncated_fields() {
+        let process = Process::new(Config {
+            max_properties: 3,
+            max_field_value_bytes: 16,
+            ..Default::default()
+        });
+
+        let gelf = json!({
+            "version": "1.1",
+            "host": "example.org",
+            "short_message": "A short message",
+            "_a": 1,
+            "_b": 2,
+            "_c": 3,
+            "_z": "A long string value",
+        });
+
+        process
+            .with_clef(gelf.to_string().as_bytes(), |clef| {
+                let clef = serde_json::to_value(&clef).expect("failed to read clef");
+
+                // The only truncated field is dropped, so there's no
+                // `_truncated_fields` tag taking up room
+                assert_eq!(json!(1), clef["a"]);
+                assert_eq!(json!(2), clef["b"]);
+                assert_eq!(Value::Null, clef["c"]);
+                assert_eq!(Value::Null, clef["z"]);
+                assert_eq!(Value::Null, clef["_truncated_fields"]);
+                assert_eq!(json!(true), clef["_truncated_properties"]);
+
+                Ok(())
+            })
+            .expect("failed to read gelf event");
+    }
+
+    #[test]
+    fn limit_field_value_bytes() {
+        let process = Process::new(Config {
+            max_field_value_bytes: 16,
+            ..Default::default()
+        });
+
+        let gelf = json!({
+            "version": "1.1",
+            "host": "example.org",
+            "short_message": "A short message",
+            "full_message": "A multi-line backtrace\nthat goes on\nand on",
+            "_user": "ünïcödé ünïcödé ünïcödé",
+            "_id": 42,
+        });
+
+        let before = METRICS.process_truncated.get();
+
+        process
+            .with_clef(gelf.to_string().as_bytes(), |clef| {
+                let clef = serde_json::to_value(&clef).expect("failed to read clef");
+
+                assert_eq!(json!("A short message"), clef["@m"]);
+                assert_eq!(json!("A multi-line …"), clef["@x"]);
+                assert_eq!(json!("ünïcödé …"), clef["user"]);
+                assert_eq!(json!(42), clef["id"]);
+                assert_eq!(json!(["@x", "user"]), clef["_truncated_fields"]);
+                assert_eq!(Value::Null, clef["_truncated_properties"]);
+
+                Ok(())
+            })
+            .expect("failed to read gelf event");
+
+        assert!(METRICS.process_truncated.get() > before);
+    }
+
+    #[test]
+    fn truncate_len_never_grows_values() {
+        assert_eq!(None, truncate_len("abc", 3));
+        assert_eq!(Some("a…".to_owned()), truncate_len("abcdef", 4));
+        assert_eq!(Some("…".to_owned()), truncate_len("abcdef", 3));
+        assert_eq!(Some("ab".to_owned()), truncate_len("abcdef", 2));
+        assert_eq!(Some("a".to_owned()), truncate_len("abcdef", 1));
+        assert_eq!(Some("".to_owned()), truncate_len("abcdef", 0));
+        assert_eq!(Some("".to_owned()), truncate_len("ü", 1));
+
+        for max in 0..8 {
+            for value in ["abcdefgh", "ünïcödé"] {
+                if let Some(short) = truncate_len(value, max) {
+                    assert!(short.len() <= max, "`{}` is longer than {} bytes", short, max);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn from_gelf_with_bom() {
+        // A message that can only be read as a stream
+        struct Stream<'a>(&'a [u8]);
+
+        impl<'a> MemRead for Stream<'a> {
+            type Reader = io::Cursor<&'a [u8]>;
+
+            fn bytes(&self) -> Option<&[u8]> {
+                None
+            }
+
+            fn into_reader(self) -> io::Result<Self::Reader> {
+                Ok(io::Cursor::new(self.0))
+            }
+        }
+
+        let gelf = json!({
+            "version": "1.1",
+            "host": "example.org",
+            "short_message": "A short message",
+        });
+
+        let mut bom = BOM.to_vec();
+        bom.extend_from_slice(gelf.to_string().as_bytes());
+
+        let mut whitespace = b" \r\n\t".to_vec();
+        whitespace.extend_from_slice(gelf.to_string().as_bytes());
+
+        let process = Process::new(Default::default());
+
+        for payload in &[&bom, &whitespace] {
+            process
+                .with_clef(&payload[..], |clef| {
+                    assert_eq!(Some("A short message"), clef.message.as_ref().map(AsRef::as_ref));
+
+                    Ok(())
+                })
+                .expect("failed to read gelf event");
+
+            process
+                .with_clef(Stream(payload), |clef| {
+                    assert_eq!(Some("A short message"), clef.message.as_ref().map(AsRef::as_ref));
+
+                    Ok(())
+                })
+                .expect("failed to read gelf event");
+        }
+    }
+
+    #[test]
+    fn from_gelf_host() {
+        struct Received<'a> {
+            bytes: &'a [u8],
+            source: Option<std::net::SocketAddr>,
+            peer: Option<std::net::SocketAddr>,
+        }
+
+        impl<'a> MemRead for Received<'a> {
+            type Reader = io::Cursor<&'a [u8]>;
+
+            fn bytes(&self) -> Option<&[u8]> {
+                Some(self.bytes)
+            }
+
+            fn into_reader(self) -> io::Result<Self::Reader> {
+                Ok(io::Cursor::new(self.bytes))
+            }
+
+            fn source(&self) -> Option<std::net::SocketAddr> {
+                self.source
+            }
+
+            fn peer(&self) -> Option<std::net::SocketAddr> {
+                self.peer
+            }
+        }
+
+        let process = Process::new(Default::default());
+
+        let addr = Some("10.0.0.7:53124".parse().unwrap());
+
+        // Messages received over HTTP have a peer and a source,
+        // UDP messages only have a source, and Unix messages have neither
+        let http = (addr, addr);
+        let udp = (addr, None);
+        let unix = (None, None);
+
+        let cases = vec![
+            (Some("example.org"), http, "example.org", false),
+            (Some("example.org"), udp, "example.org", false),
+            (Some("example.org"), unix, "example.org", false),
+            (None, http, "10.0.0.7", true),
+            (None, udp, "unknown", true),
+            (None, unix, "unknown", true),
+        ];
+
+        for (host, (source, peer), expected, defaulted) in cases {
+            let mut gelf = json!({
+                "version": "1.1",
+                "short_message": "A short message",
+            });
+
+            if let Some(host) = host {
+                gelf["host"] = json!(host);
+            }
+
+            let gelf = gelf.to_string();
+
+            let before = METRICS.host_defaulted.get();
+
+            process
+                .with_clef(
+                    Received {
+                        bytes: gelf.as_bytes(),
+                        source,
+                        peer,
+                    },
+                    |clef| {
+                        assert_eq!(Some(&json!(expected)), clef.additional.get(&Str::Borrowed("host")));
+
+                        Ok(())
+                    },
+                )
+                .expect("failed to read gelf event");
+
+            if defaulted {
+                assert!(METRICS.host_defaulted.get() > before);
+            }
+        }
+    }
+
+    #[test]
+    fn from_gelf_source_location() {
+        let cases = vec![
+            (
+                json!({ "facility": "app", "file": "main.rs", "line": 42 }),
+                json!({ "facility": "app", "file": "main.rs", "line": 42 }),
+            ),
+            (json!({ "facility": "app" }), json!({ "facility": "app" })),
+            (json!({ "file": "main.rs", "line": "42" }), json!({ "file": "main.rs", "line": 42 })),
+            (json!({ "line": "not a line" }), json!({})),
+            (json!({}), json!({})),
+        ];
+
+        let process = Process::new(Default::default());
+
+        for (fields, expected) in cases {
+            let mut gelf = json!({
+                "version": "1.1",
+                "short_message": "A short message",
+            });
+
+            for (k, v) in fields.as_object().expect("fields are an object") {
+                gelf[k] = v.clone();
+            }
+
+            process
+                .with_clef(gelf.to_string().as_bytes(), |clef| {
+                    let clef = serde_json::to_value(&clef).expect("failed to read clef");
+
+                    for name in &["facility", "file", "line"] {
+                        assert_eq!(
+                            expected.get(name),
+                            clef.get(name),
+                            "unexpected `{}` for GELF fields {}",
+                            name,
+                            fields
+                        );
+                    }
+
+                    Ok(())
+                })
+                .expect("failed to read gelf event");
+        }
+    }
+
+    #[test]
+    fn from_gelf_version() {
+        let cases = vec![
+            (Some("1.0"), true),
+            (Some("1.1"), true),
+            (Some("2.0"), false),
+            (None, false),
+        ];
+
+        let process = Process::new(Default::default());
+
+        for (version, expected) in cases {
+            let mut gelf = json!({
+                "host": "example.org",
+                "short_message": "A short message",
+            });
+
+            if let Some(version) = version {
+                gelf["version"] = version.into();
+            }
+
+            let before = METRICS.receive_invalid_version.get();
+
+            let read = process.with_clef(gelf.to_string().as_bytes(), |_| Ok(()));
+
+            assert_eq!(
+                expected,
+                read.is_ok(),
+                "unexpected result for GELF version {:?}",
+                version
+            );
+
+            if !expected {
+                assert!(METRICS.receive_invalid_version.get() > before);
+            }
+        }
+    }
+
+    #[test]
+    fn from_clef_passthrough() {
+        let process = Process::new(Config {
+            input_format: InputFormat::Clef,
+            ..Default::default()
+        });
+
+        let clef = json!({
+            "@t": "2013-11-21T17:11:02Z",
+            "@mt": "Hello, {user_id}",
+            "@l": "Warning",
+            "user_id": 9001,
+            "_not_additional": true
+        });
+
+        process
+            .with_clef(clef.to_string().as_bytes(), |read| {
+                let read = serde_json::to_value(&read).expect("failed to read clef");
+
+                assert_eq!(clef, read);
+
+                Ok(())
+            })
+            .expect("failed to read clef event");
+
+        // Timestamps and levels are defaulted
+        process
+            .with_clef(json!({ "@m": "Hello" }).to_string().as_bytes(), |read| {
+                assert!(read.timestamp.is_some());
+                assert_eq!(Some("Information"), read.level.as_ref().map(AsRef::as_ref));
+
+                Ok(())
+            })
+            .expect("failed to read clef event");
+
+        // GELF isn't valid CLEF
+        let gelf = json!({
+            "version": "1.1",
+            "host": "example.org",
+            "short_message": "A short message",
+        });
+
+        let err = process
+            .with_clef(gelf.to_string().as_bytes(), |_| Ok(()))
+            .expect_err("expected GELF to fail");
+
+        assert_eq!(ErrorKind::Parse, err.kind());
+    }
+
+    #[test]
+    fn enrich_without_overriding_message_fields() {
+        std::env::set_var("SQELF_TEST_ENRICH_REGION", "us-east-1");
+
+        let process = Process::new(Config {
+            enrich: vec![
+                ("deployment", "prod"),
+                ("region", "${SQELF_TEST_ENRICH_REGION}"),
+                ("zone", "${SQELF_TEST_ENRICH_REGION}-${SQELF_TEST_ENRICH_UNSET}a"),
+                ("host", "enriched.example.org"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect(),
+            ..Default::default()
+        });
+
+        let gelf = json!({
+            "version": "1.1",
+            "host": "example.org",
+            "short_message": "A short message",
+            "_deployment": "staging",
+        });
+
+        process
+            .with_clef(gelf.to_string().as_bytes(), |clef| {
+                assert_eq!(Some(&json!("staging")), clef.additional.get(&Str::Borrowed("deployment")));
+                assert_eq!(Some(&json!("example.org")), clef.additional.get(&Str::Borrowed("host")));
+                assert_eq!(Some(&json!("us-east-1")), clef.additional.get(&Str::Borrowed("region")));
+                assert_eq!(Some(&json!("us-east-1-a")), clef.additional.get(&Str::Borrowed("zone")));
+
+                Ok(())
+            })
+            .expect("failed to read gelf event");
+    }
+
+    #[test]
+    fn attach_source_ip() {
+        struct Received<'a>(&'a [u8]);
+
+        impl<'a> MemRead for Received<'a> {
+            type Reader = io::Cursor<&'a [u8]>;
+
+            fn bytes(&self) -> Option<&[u8]> {
+                Some(self.0)
+            }
+
+            fn into_reader(self) -> io::Result<Self::Reader> {
+                Ok(io::Cursor::new(self.0))
+            }
+
+            fn source(&self) -> Option<std::net::SocketAddr> {
+                Some("10.0.0.7:53124".parse().unwrap())
+            }
+        }
+
+        let gelf = json!({
+            "version": "1.1",
+            "host": "example.org",
+            "short_message": "A short message",
+        })
+        .to_string();
+
+        let source_ip = |attach_source_ip| {
+            let process = Process::new(Config {
+                attach_source_ip,
+                ..Default::default()
+            });
+
+            let mut source_ip = None;
+            process
+                .with_clef(Received(gelf.as_bytes()), |clef| {
+                    source_ip = clef.additional.get(&Str::Borrowed(SOURCE_IP)).cloned();
+
+                    Ok(())
+                })
+                .expect("failed to read gelf event");
+
+            source_ip
+        };
+
+        assert_eq!(Some(json!("10.0.0.7")), source_ip(true));
+        assert_eq!(None, source_ip(false));
+    }
+
+    #[test]
+    fn rename_properties() {
+        let process = Process::new(Config {
+            rename: vec![
+                ("host", "MachineName"),
+                ("_app", "Application"),
+                ("stamp", "@t"),
+                ("a", "b"),
+                ("b", "a"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect(),
+            enrich: vec![("app".to_owned(), "enriched".to_owned())].into_iter().collect(),
+            ..Default::default()
+        });
+
+        let gelf = json!({
+            "version": "1.1",
+            "host": "example.org",
+            "short_message": "A short message",
+            "timestamp": 1385053862.3072,
+            "_app": "Billing",
+            "_stamp": "not a timestamp",
+            "_MachineName": "payload.example.org",
+            "_a": 1,
+            "_b": 2,
+        });
+
+        process
+            .with_clef(gelf.to_string().as_bytes(), |clef| {
+                let expected = json!({
+                    "@t": "2013-11-21T17:11:02.307000000Z",
+                    "@l": "Information",
+                    "@m": "A short message",
+                    "@@t": "not a timestamp",
+                    "MachineName": "example.org",
+                    "__MachineName": "payload.example.org",
+                    "Application": "Billing",
+                    "app": "enriched",
+                    "a": 2,
+                    "b": 1,
+                });
+
+                let clef = serde_json::to_value(&clef).expect("failed to read clef");
+
+                assert_eq!(expected, clef);
+
+                Ok(())
+            })
+            .expect("failed to read gelf event");
+    }
+
+    #[test]
+    fn enrich_by_facility() {
+        std::env::set_var("SQELF_TEST_FACILITY_TEAM", "identity");
+
+        let fields = |fields: &[(&str, &str)]| -> HashMap<String, String> {
+            fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+
+        let process = Process::new(Config {
+            enrich: fields(&[("Application", "Unknown")]),
+            facilities: vec![
+                ("auth".to_owned(), fields(&[("Application", "AuthService"), ("Team", "${SQELF_TEST_FACILITY_TEAM}")])),
+                ("billing".to_owned(), fields(&[("Application", "BillingService")])),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        });
+
+        let cases = vec![
+            (Some("auth"), "AuthService", Some("identity")),
+            (Some("billing"), "BillingService", None),
+            (Some("kern"), "Unknown", None),
+            (None, "Unknown", None),
+        ];
+
+        for (facility, application, team) in cases {
+            let mut gelf = json!({
+                "version": "1.1",
+                "host": "example.org",
+                "short_message": "A short message",
+            });
+
+            if let Some(facility) = facility {
+                gelf["facility"] = json!(facility);
+            }
+
+            process
+                .with_clef(gelf.to_string().as_bytes(), |clef| {
+                    assert_eq!(Some(&json!(application)), clef.additional.get(&Str::Borrowed("Application")));
+                    assert_eq!(team.map(|team| json!(team)).as_ref(), clef.additional.get(&Str::Borrowed("Team")));
+                    assert_eq!(facility.map(|facility| json!(facility)).as_ref(), clef.additional.get(&Str::Borrowed("facility")));
+
+                    Ok(())
+                })
+                .expect("failed to read gelf event");
+        }
+    }
+
+    #[test]
+    fn from_gelf_level() {
+        let cases = vec![
+            (Some(0), "Fatal"),
+            (Some(2), "Fatal"),
+            (Some(3), "Error"),
+            (Some(4), "Warning"),
+            (Some(5), "Information"),
+            (Some(6), "Information"),
+            (Some(7), "Debug"),
+            (Some(8), "Debug"),
+            (None, "Information"),
+        ];
+
+        let process = Process::new(Default::default());
+
+        for (level, expected) in cases {
+            let mut gelf = json!({
+                "version": "1.1",
+                "host": "example.org",
+                "short_message": "A short message",
+            });
+
+            if let Some(level) = level {
+                gelf["level"] = level.into();
+            }
+
+            process
+                .with_clef(gelf.to_string().as_bytes(), |clef| {
+                    assert_eq!(
+                        Some(expected),
+                        clef.level.as_ref().map(AsRef::as_ref),
+                        "unexpected CLEF level for GELF level {:?}",
+                        level
+                    );
+
+                    Ok(())
+                })
+                .expect("failed to read gelf event");
+        }
+    }
+
     #[test]
     fn from_gelf_inner_json() {
         let clef = json!({
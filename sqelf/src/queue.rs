@@ -0,0 +1,374 @@
+/*!
+A bounded queue between receiving and processing messages.
+
+When the queue is full, what happens to new messages depends on the
+configured `OverflowPolicy`.
+*/
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures::{task::AtomicTask, Async, AsyncSink, Poll, Sink, StartSend, Stream};
+
+/**
+What to do with a new message when the queue is full.
+*/
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /**
+    Wait for space in the queue before receiving any more messages.
+
+    While waiting, no sockets are read. For UDP, this means the OS will drop
+    datagrams instead once its receive buffer is full. This policy is best
+    suited to HTTP, where senders will wait for a response.
+    */
+    Block,
+    /**
+    Drop the oldest message in the queue to make space for the new one.
+
+    This keeps the latency of processed messages low, and is best suited to
+    UDP when recent messages are more valuable than old ones.
+    */
+    DropOldest,
+    /**
+    Drop the new message.
+
+    This is the cheapest policy, and is suited to UDP.
+    */
+    #[default]
+    DropNewest,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = ParseOverflowPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_ascii_lowercase() {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop_newest" => Ok(OverflowPolicy::DropNewest),
+            _ => Err(ParseOverflowPolicyError(s.to_owned())),
+        }
+    }
+}
+
+/**
+An overflow policy that isn't supported.
+*/
+#[derive(Debug)]
+pub struct ParseOverflowPolicyError(String);
+
+impl fmt::Display for ParseOverflowPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` isn't a supported overflow policy; expected `block`, `drop_oldest`, or `drop_newest`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseOverflowPolicyError {}
+
+/**
+Create a bounded queue.
+
+Messages are pushed into the sender, and popped from the receiver.
+The receiver completes once the sender is dropped and the queue is empty.
+Sending fails once the receiver is dropped.
+*/
+pub(crate) fn channel<T>(capacity: usize, policy: OverflowPolicy) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        items: Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity: capacity.max(1),
+        policy,
+        sender_task: AtomicTask::new(),
+        receiver_task: AtomicTask::new(),
+        sender_closed: AtomicBool::new(false),
+        receiver_closed: AtomicBool::new(false),
+    });
+
+    (
+        Sender {
+            shared: shared.clone(),
+            blocked: false,
+        },
+        Receiver { shared },
+    )
+}
+
+struct Shared<T> {
+    items: Mutex<VecDeque<T>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    sender_task: AtomicTask,
+    receiver_task: AtomicTask,
+    sender_closed: AtomicBool,
+    receiver_closed: AtomicBool,
+}
+
+impl<T> Shared<T> {
+    fn try_push(&self, item: T) -> Result<(), T> {
+        let mut items = self.items.lock().unwrap_or_else(|e| e.into_inner());
+
+        if items.len() < self.capacity {
+            items.push_back(item);
+            drop(items);
+
+            self.receiver_task.notify();
+
+            Ok(())
+        } else {
+            Err(item)
+        }
+    }
+}
+
+/**
+The sending half of a queue.
+*/
+pub(crate) struct Sender<T> {
+    shared: Arc<Shared<T>>,
+    // Whether the current message has already been counted as blocked
+    blocked: bool,
+}
+
+impl<T> Sender<T> {
+    /**
+    Get a handle to the number of messages in the queue.
+
+    The handle can still be used after the sender is dropped.
+    */
+    pub(crate) fn len(&self) -> Len<T> {
+        Len {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Sink for Sender<T> {
+    type SinkItem = T;
+    type SinkError = ();
+
+    fn start_send(&mut self, item: T) -> StartSend<T, ()> {
+        // If nothing is receiving messages then the message can't be processed
+        if self.shared.receiver_closed.load(Ordering::Acquire) {
+            return Err(());
+        }
+
+        let item = match self.shared.try_push(item) {
+            Ok(()) => {
+                self.blocked = false;
+
+                return Ok(AsyncSink::Ready);
+            }
+            Err(item) => item,
+        };
+
+        match self.shared.policy {
+            OverflowPolicy::Block => {
+                // Register before trying again so a message popped
+                // in the meantime will wake us up
+                self.shared.sender_task.register();
+
+                match self.shared.try_push(item) {
+                    Ok(()) => {
+                        self.blocked = false;
+
+                        Ok(AsyncSink::Ready)
+                    }
+                    Err(item) => {
+                        // The same message is offered again each time the
+                        // sink is polled, so only count it the first time
+                        if !self.blocked {
+                            self.blocked = true;
+                            increment!(process_queue_blocked);
+                        }
+
+                        Ok(AsyncSink::NotReady(item))
+                    }
+                }
+            }
+            OverflowPolicy::DropOldest => {
+                let mut items = self.shared.items.lock().unwrap_or_else(|e| e.into_inner());
+
+                if items.len() >= self.shared.capacity {
+                    items.pop_front();
+                    increment!(process_queue_evicted);
+                }
+
+                items.push_back(item);
+                drop(items);
+
+                self.shared.receiver_task.notify();
+
+                Ok(AsyncSink::Ready)
+            }
+            OverflowPolicy::DropNewest => {
+                increment!(process_queue_full);
+
+                Ok(AsyncSink::Ready)
+            }
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), ()> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.sender_closed.store(true, Ordering::Release);
+        self.shared.receiver_task.notify();
+    }
+}
+
+/**
+The number of messages in a queue.
+*/
+pub(crate) struct Len<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Len<T> {
+    pub(crate) fn get(&self) -> usize {
+        self.shared.items.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+/**
+The receiving half of a queue.
+*/
+pub(crate) struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    fn try_pop(&self) -> Option<T> {
+        let item = self
+            .shared
+            .items
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop_front();
+
+        if item.is_some() {
+            self.shared.sender_task.notify();
+        }
+
+        item
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<T>, ()> {
+        if let Some(item) = self.try_pop() {
+            return Ok(Async::Ready(Some(item)));
+        }
+
+        // Check whether the sender is closed before registering,
+        // so any messages sent before it was closed are still popped
+        let closed = self.shared.sender_closed.load(Ordering::Acquire);
+
+        // Register before trying again so a message pushed
+        // in the meantime will wake us up
+        self.shared.receiver_task.register();
+
+        match self.try_pop() {
+            Some(item) => Ok(Async::Ready(Some(item))),
+            None if closed => Ok(Async::Ready(None)),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_closed.store(true, Ordering::Release);
+        self.shared.sender_task.notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{future, Future};
+
+    fn send_all(policy: OverflowPolicy, items: Vec<i32>) -> Vec<i32> {
+        let (mut tx, rx) = channel(2, policy);
+
+        for item in items {
+            match tx.start_send(item) {
+                Ok(AsyncSink::Ready) => (),
+                _ => panic!("expected the item to be accepted"),
+            }
+        }
+
+        drop(tx);
+
+        rx.collect().wait().expect("failed to receive")
+    }
+
+    #[test]
+    fn send_after_receiver_closed_fails() {
+        let (mut tx, rx) = channel(2, OverflowPolicy::Block);
+
+        assert!(matches!(tx.start_send(1), Ok(AsyncSink::Ready)));
+
+        drop(rx);
+
+        assert!(tx.start_send(2).is_err());
+    }
+
+    #[test]
+    fn drop_newest() {
+        assert_eq!(vec![1, 2], send_all(OverflowPolicy::DropNewest, vec![1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn drop_oldest() {
+        assert_eq!(vec![3, 4], send_all(OverflowPolicy::DropOldest, vec![1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn block() {
+        let (mut tx, mut rx) = channel(2, OverflowPolicy::Block);
+
+        future::lazy(move || {
+            assert!(tx.start_send(1).unwrap().is_ready());
+            assert!(tx.start_send(2).unwrap().is_ready());
+            assert!(tx.start_send(3).unwrap().is_not_ready());
+            assert!(tx.start_send(3).unwrap().is_not_ready());
+
+            // The message is only counted as blocked once
+            assert!(tx.blocked);
+
+            // Popping a message makes space for another
+            assert_eq!(Async::Ready(Some(1)), rx.poll().unwrap());
+            assert!(tx.start_send(3).unwrap().is_ready());
+            assert!(!tx.blocked);
+
+            drop(tx);
+
+            let rest: Vec<_> = rx.collect().wait().expect("failed to receive");
+            assert_eq!(vec![2, 3], rest);
+
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+/*!
+Rate limiting for datagrams by their source address.
+*/
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    time::Instant,
+};
+
+use serde_json::json;
+
+use crate::diagnostics::*;
+
+/**
+Rate limiter configuration.
+*/
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Config {
+    /**
+    The number of datagrams per second allowed from a single source.
+    */
+    pub(crate) per_sec: u32,
+    /**
+    The number of datagrams a single source can send at once
+    before being limited.
+    */
+    pub(crate) burst: u32,
+    /**
+    The maximum number of sources to track.
+
+    If this value is reached then the least recently seen source is forgotten.
+    */
+    pub(crate) capacity: usize,
+}
+
+/**
+A token bucket rate limiter for each source address.
+
+Sources are tracked in least-recently-seen order so that a flood
+of distinct (possibly spoofed) sources can't exhaust memory.
+*/
+pub(crate) struct RateLimit {
+    config: Config,
+    counter: u64,
+    by_source: HashMap<IpAddr, Bucket>,
+    by_seen: BTreeMap<u64, IpAddr>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    seen: u64,
+    limited: bool,
+}
+
+impl RateLimit {
+    pub(crate) fn new(config: Config) -> Self {
+        RateLimit {
+            config,
+            counter: 0,
+            by_source: HashMap::new(),
+            by_seen: BTreeMap::new(),
+        }
+    }
+
+    /**
+    Check whether a datagram from the given source is allowed.
+
+    The first datagram to be limited from a source is reported so the
+    source can be identified.
+    */
+    pub(crate) fn allow(&mut self, source: IpAddr) -> bool {
+        self.allow_at(source, Instant::now())
+    }
+
+    fn allow_at(&mut self, source: IpAddr, now: Instant) -> bool {
+        let seen = self.counter;
+        self.counter = self.counter.wrapping_add(1);
+
+        if !self.by_source.contains_key(&source) && self.by_source.len() >= self.config.capacity {
+            self.forget_least_recently_seen();
+        }
+
+        let burst = f64::from(self.config.burst.max(1));
+        let per_sec = f64::from(self.config.per_sec);
+
+        let bucket = self.by_source.entry(source).or_insert(Bucket {
+            tokens: burst,
+            last_refill: now,
+            seen,
+            limited: false,
+        });
+
+        self.by_seen.remove(&bucket.seen);
+        self.by_seen.insert(seen, source);
+        bucket.seen = seen;
+
+        // Refill the bucket for the time since it was last checked
+        let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.limited = false;
+
+            true
+        } else {
+            if !bucket.limited {
+                bucket.limited = true;
+
+                emit_debug(
+                    "GELF source {Source} is being rate limited",
+                    json!({
+                        "Source": source,
+                        "PerSec": self.config.per_sec,
+                        "Burst": self.config.burst,
+                    }),
+                );
+            }
+
+            false
+        }
+    }
+
+    fn forget_least_recently_seen(&mut self) {
+        let oldest = self.by_seen.keys().next().copied();
+
+        if let Some(source) = oldest.and_then(|oldest| self.by_seen.remove(&oldest)) {
+            self.by_source.remove(&source);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn limit(per_sec: u32, burst: u32, capacity: usize) -> RateLimit {
+        RateLimit::new(Config {
+            per_sec,
+            burst,
+            capacity,
+        })
+    }
+
+    #[test]
+    fn allow_burst_then_refill() {
+        let mut limit = limit(2, 3, 16);
+
+        let source = "127.0.0.1".parse().unwrap();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limit.allow_at(source, start));
+        }
+        assert!(!limit.allow_at(source, start));
+
+        // Half a second refills a single token
+        let later = start + Duration::from_millis(500);
+        assert!(limit.allow_at(source, later));
+        assert!(!limit.allow_at(source, later));
+
+        // Other sources aren't affected
+        assert!(limit.allow_at("127.0.0.2".parse().unwrap(), later));
+    }
+
+    #[test]
+    fn forget_least_recently_seen_past_capacity() {
+        let mut limit = limit(1, 1, 2);
+
+        let a = "10.0.0.1".parse().unwrap();
+        let b = "10.0.0.2".parse().unwrap();
+        let c = "10.0.0.3".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limit.allow_at(a, now));
+        assert!(limit.allow_at(b, now));
+        assert!(!limit.allow_at(a, now));
+
+        // `b` is the least recently seen, so it's forgotten
+        assert!(limit.allow_at(c, now));
+
+        assert_eq!(2, limit.by_source.len());
+        assert_eq!(2, limit.by_seen.len());
+        assert!(limit.by_source.contains_key(&a));
+        assert!(!limit.by_source.contains_key(&b));
+
+        // A forgotten source starts with a full bucket
+        assert!(limit.allow_at(b, now));
+    }
+}
@@ -1,7 +1,8 @@
 use std::{
     cmp,
-    collections::{hash_map, BTreeMap, HashMap},
+    collections::{btree_map, hash_map, BTreeMap, HashMap},
     io::{self, Read},
+    net::SocketAddr,
     time::{self, Duration, SystemTime},
 };
 
@@ -9,14 +10,15 @@ use bytes::{Buf, Bytes, IntoBuf};
 use libflate::{gzip, zlib};
 
 use crate::{
-    error::Error,
+    error::{Error, ErrorKind},
     io::MemRead,
 };
 
 /**
 GELF receiver configuration.
 */
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     /**
     The maximum number of incomplete chunked messages.
@@ -26,6 +28,16 @@ pub struct Config {
     */
     pub incomplete_capacity: usize,
     /**
+    The maximum number of incomplete chunked messages to track at once.
+
+    If this value is reached then the oldest incomplete message is
+    evicted to make room for a new one. This bounds memory used by senders
+    that start many chunked messages without finishing them, independently
+    of `incomplete_timeout_ms`. If this value is `None` then only
+    `incomplete_capacity` applies.
+    */
+    pub chunk_max_inflight: Option<usize>,
+    /**
     The maximum number of chunks for a single chunked message.
 
     Messages with more than this value will be discarded.
@@ -39,14 +51,22 @@ pub struct Config {
     does not reset as subsequent chunks arrive.
     */
     pub incomplete_timeout_ms: u64,
+    /**
+    The maximum size in bytes of a compressed message after it's been decompressed.
+
+    Messages that decompress to more than this value will fail to be read.
+    */
+    pub max_decompressed_bytes: usize,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             incomplete_capacity: 1024,
+            chunk_max_inflight: None,
             max_chunks_per_message: 128,
             incomplete_timeout_ms: 5 * 1000,
+            max_decompressed_bytes: 8 * 1024 * 1024,
         }
     }
 }
@@ -136,9 +156,10 @@ impl Gelf {
             // If the chunk completes the message then it
             // will be returned
             self.chunked(src)
+                .map_err(|e| e.with_kind(ErrorKind::Reassembly))
         } else {
             // Return a message containing a single chunk
-            Ok(Message::single(magic.and_then(Compression::detect), src))
+            Ok(self.single(Compression::detect(&src), src))
         }
     }
 
@@ -154,9 +175,7 @@ impl Gelf {
                 seq_count: 1,
                 ..
             } => {
-                let magic = Message::peek_magic_bytes(&src);
-
-                return Ok(Message::single(magic.and_then(Compression::detect), src));
+                Ok(self.single(Compression::detect(&src), src))
             }
             // If the message has too many chunks then discard it
             ChunkHeader { seq_count, .. } if seq_count > self.config.max_chunks_per_message => {
@@ -178,6 +197,10 @@ impl Gelf {
         }
     }
 
+    fn single(&self, compression: Option<Compression>, src: Bytes) -> Option<Message> {
+        Message::single(compression, src, self.config.max_decompressed_bytes)
+    }
+
     fn gc(&mut self) -> Result<(), Error> {
         // Check the capacity of the incomplete chunk list
         // If we're past the threshold then drop *all* chunks,
@@ -201,12 +224,36 @@ impl Gelf {
         for (by_arrival, by_id) in to_remove {
             self.by_id.chunks.remove(&by_id);
             self.by_arrival.chunks.remove(&by_arrival);
+
+            increment!(chunk_expired);
         }
 
         Ok(())
     }
 
+    fn make_room(&mut self) {
+        // Evict the oldest incomplete messages until there's room for a new one
+        if let Some(max_inflight) = self.config.chunk_max_inflight {
+            while self.by_arrival.chunks.len() >= max_inflight.max(1) {
+                let oldest = self.by_arrival.chunks.keys().next().copied();
+
+                match oldest.and_then(|oldest| self.by_arrival.chunks.remove(&oldest)) {
+                    Some(id) => {
+                        self.by_id.chunks.remove(&id);
+
+                        increment!(chunk_evicted);
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+
     fn push(&mut self, header: ChunkHeader, chunk: Chunk) -> Result<Option<Message>, Error> {
+        if !self.by_id.chunks.contains_key(&header.id) {
+            self.make_room();
+        }
+
         match self.by_id.chunks.entry(header.id) {
             // Begin a new message with the given chunk
             hash_map::Entry::Vacant(entry) => {
@@ -231,13 +278,21 @@ impl Gelf {
                     );
                 }
 
-                chunks.insert(chunk);
+                // Senders may retransmit chunks, so only the first
+                // chunk for each sequence number is kept
+                if !chunks.insert(chunk) {
+                    increment!(chunk_duplicate);
+                }
+
                 if chunks.is_complete() {
                     let (_, (chunks, arrival)) = entry.remove_entry();
                     self.by_arrival.chunks.remove(&arrival);
 
+                    increment!(chunk_reassembled);
+
                     Ok(Message::chunked(
-                        chunks.inner.into_iter().map(|(_, chunk)| chunk),
+                        chunks.inner.into_values(),
+                        self.config.max_decompressed_bytes,
                     ))
                 } else {
                     Ok(None)
@@ -269,8 +324,21 @@ impl Chunks {
         }
     }
 
-    fn insert(&mut self, chunk: Chunk) {
-        self.inner.insert(chunk.seq, chunk.bytes);
+    /**
+    Insert a chunk into the message.
+
+    If a chunk with the same sequence number has already been inserted
+    then the new chunk is ignored and `false` is returned.
+    */
+    fn insert(&mut self, chunk: Chunk) -> bool {
+        match self.inner.entry(chunk.seq) {
+            btree_map::Entry::Vacant(entry) => {
+                entry.insert(chunk.bytes);
+
+                true
+            }
+            btree_map::Entry::Occupied(_) => false,
+        }
     }
 
     fn is_complete(&self) -> bool {
@@ -282,7 +350,12 @@ impl Chunks {
 A raw GELF message.
 */
 #[derive(Debug, PartialEq, Eq)]
-pub struct Message(MessageInner);
+pub struct Message {
+    inner: MessageInner,
+    max_decompressed_bytes: usize,
+    source: Option<SocketAddr>,
+    peer: Option<SocketAddr>,
+}
 
 #[derive(Debug, PartialEq, Eq)]
 enum MessageInner {
@@ -344,35 +417,81 @@ impl ChunkHeader {
 enum Compression {
     Gzip,
     Zlib,
+    #[cfg(feature = "zstd")]
+    Zstd,
 }
 
 impl Message {
     const MAGIC_CHUNKED: [u8; 2] = [0x1e, 0x0f];
 
-    fn single(compression: Option<Compression>, src: Bytes) -> Option<Self> {
-        if src.len() == 0 {
+    fn single(
+        compression: Option<Compression>,
+        src: Bytes,
+        max_decompressed_bytes: usize,
+    ) -> Option<Self> {
+        if src.is_empty() {
             return None;
         }
 
         debug_assert_eq!(
-            Self::peek_magic_bytes(&src).and_then(Compression::detect),
+            Compression::detect(&src),
             compression
         );
 
-        Some(Message(MessageInner::Single {
-            compression,
-            bytes: src,
-        }))
+        Some(Message {
+            inner: MessageInner::Single {
+                compression,
+                bytes: src,
+            },
+            max_decompressed_bytes,
+            source: None,
+            peer: None,
+        })
     }
 
-    fn chunked(chunks: impl IntoIterator<Item = Bytes>) -> Option<Self> {
+    fn chunked(
+        chunks: impl IntoIterator<Item = Bytes>,
+        max_decompressed_bytes: usize,
+    ) -> Option<Self> {
         let chunks: Vec<_> = chunks.into_iter().collect();
 
-        if chunks.len() == 0 {
+        if chunks.is_empty() {
             return None;
         }
 
-        Some(Message(MessageInner::Chunked { chunks }))
+        Some(Message {
+            inner: MessageInner::Chunked { chunks },
+            max_decompressed_bytes,
+            source: None,
+            peer: None,
+        })
+    }
+
+    /**
+    The address the message was received from, if it's known.
+
+    For chunked messages, this is the address of the last chunk received.
+    */
+    pub fn source(&self) -> Option<SocketAddr> {
+        self.source
+    }
+
+    pub(crate) fn set_source(&mut self, source: Option<SocketAddr>) {
+        self.source = source;
+    }
+
+    /**
+    The address of the peer the message was received from over a connection,
+    if it's known.
+
+    Messages received in datagrams don't have a peer.
+    */
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    pub(crate) fn set_peer(&mut self, peer: Option<SocketAddr>) {
+        self.peer = peer;
     }
 
     fn peek_magic_bytes(src: &[u8]) -> Option<[u8; 2]> {
@@ -387,12 +506,11 @@ impl Message {
     }
 
     fn compression(&self) -> Option<Compression> {
-        match &self.0 {
+        match &self.inner {
             MessageInner::Single { compression, .. } => *compression,
             MessageInner::Chunked { chunks } => chunks
                 .first()
-                .and_then(|chunk| Self::peek_magic_bytes(&chunk))
-                .and_then(Compression::detect),
+                .and_then(|chunk| Compression::detect(chunk)),
         }
     }
 }
@@ -400,28 +518,47 @@ impl Message {
 impl MemRead for Message {
     type Reader = Reader;
 
+    fn source(&self) -> Option<SocketAddr> {
+        self.source
+    }
+
+    fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
     fn bytes(&self) -> Option<&[u8]> {
-        match &self.0 {
+        match &self.inner {
             MessageInner::Single {
                 bytes,
                 compression: None,
-            } => Some(&*bytes),
+            } => Some(bytes),
             _ => None,
         }
     }
 
     fn into_reader(self) -> io::Result<Reader> {
         let compression = self.compression();
+        let max = self.max_decompressed_bytes;
 
         let body = ChunkRead {
             chunk: 0,
             cursor: 0,
-            msg: self.0,
+            msg: self.inner,
         };
 
         let reader = match compression {
-            Some(Compression::Gzip) => Reader(ReaderInner::Gzip(gzip::Decoder::new(body)?)),
-            Some(Compression::Zlib) => Reader(ReaderInner::Zlib(zlib::Decoder::new(body)?)),
+            Some(Compression::Gzip) => Reader(ReaderInner::Gzip(Box::new(Decompress::new(
+                gzip::Decoder::new(body)?,
+                max,
+            )))),
+            Some(Compression::Zlib) => {
+                Reader(ReaderInner::Zlib(Decompress::new(zlib::Decoder::new(body)?, max)))
+            }
+            #[cfg(feature = "zstd")]
+            Some(Compression::Zstd) => Reader(ReaderInner::Zstd(Box::new(Decompress::new(
+                zstd::stream::read::Decoder::new(body)?,
+                max,
+            )))),
             None => Reader(ReaderInner::Uncompressed(body)),
         };
 
@@ -436,8 +573,10 @@ pub struct Reader(ReaderInner);
 
 enum ReaderInner {
     Uncompressed(ChunkRead),
-    Gzip(gzip::Decoder<ChunkRead>),
-    Zlib(zlib::Decoder<ChunkRead>),
+    Gzip(Box<Decompress<gzip::Decoder<ChunkRead>>>),
+    Zlib(Decompress<zlib::Decoder<ChunkRead>>),
+    #[cfg(feature = "zstd")]
+    Zstd(Box<Decompress<zstd::stream::read::Decoder<'static, io::BufReader<ChunkRead>>>>),
 }
 
 impl Read for Reader {
@@ -446,10 +585,54 @@ impl Read for Reader {
             ReaderInner::Uncompressed(msg) => msg.read(b),
             ReaderInner::Gzip(msg) => msg.read(b),
             ReaderInner::Zlib(msg) => msg.read(b),
+            #[cfg(feature = "zstd")]
+            ReaderInner::Zstd(msg) => msg.read(b),
         }
     }
 }
 
+/**
+A reader for decompressed bytes that fails if too many are read.
+
+This guards against small payloads that decompress into
+very large ones.
+*/
+struct Decompress<R> {
+    inner: R,
+    remaining: usize,
+}
+
+impl<R> Decompress<R> {
+    fn new(inner: R, max: usize) -> Self {
+        Decompress {
+            inner,
+            remaining: max,
+        }
+    }
+}
+
+impl<R> Read for Decompress<R>
+where
+    R: Read,
+{
+    fn read(&mut self, b: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(b)?;
+
+        if read > self.remaining {
+            increment!(decompress_overflow);
+
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the decompressed message is too large",
+            ));
+        }
+
+        self.remaining -= read;
+
+        Ok(read)
+    }
+}
+
 struct ChunkRead {
     chunk: usize,
     cursor: usize,
@@ -460,7 +643,7 @@ impl Read for ChunkRead {
     fn read(&mut self, b: &mut [u8]) -> io::Result<usize> {
         match &mut self.msg {
             MessageInner::Single { bytes, .. } => {
-                if b.len() == 0 {
+                if b.is_empty() {
                     return Ok(0);
                 }
 
@@ -476,7 +659,7 @@ impl Read for ChunkRead {
                 let mut b = b;
                 let mut total = 0;
 
-                while b.len() > 0 {
+                while !b.is_empty() {
                     if let Some(bytes) = chunks.get(self.chunk) {
                         let readable = &bytes[self.cursor..];
 
@@ -506,9 +689,18 @@ impl Read for ChunkRead {
 impl Compression {
     const MAGIC_GZIP: [u8; 2] = [0x1f, 0x8b];
     const MAGIC_ZLIB: u8 = 0x78;
+    #[cfg(feature = "zstd")]
+    const MAGIC_ZSTD: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+    fn detect(src: &[u8]) -> Option<Compression> {
+        #[cfg(feature = "zstd")]
+        {
+            if src.starts_with(&Self::MAGIC_ZSTD) {
+                return Some(Compression::Zstd);
+            }
+        }
 
-    fn detect(header: [u8; 2]) -> Option<Compression> {
-        match header {
+        match Message::peek_magic_bytes(src)? {
             Self::MAGIC_GZIP => Some(Compression::Gzip),
             header
                 if header[0] == Self::MAGIC_ZLIB
@@ -531,6 +723,8 @@ mod tests {
 
     use byteorder::{BigEndian, ByteOrder};
 
+    use crate::diagnostics::METRICS;
+
     fn chunk(id: u64, seq_num: u8, seq_total: u8, bytes: &[u8]) -> Bytes {
         let mut header = vec![0x1e, 0x0f];
 
@@ -590,12 +784,12 @@ mod tests {
             .expect("failed to decode message")
             .expect("missing message value");
 
-        let expected = Message(MessageInner::Single {
+        let expected = MessageInner::Single {
             compression: None,
             bytes: Bytes::from(b"Hello!" as &[u8]),
-        });
+        };
 
-        assert_eq!(expected, msg);
+        assert_eq!(expected, msg.inner);
     }
 
     #[test]
@@ -652,6 +846,81 @@ mod tests {
         assert_eq!("Hello!", read);
     }
 
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn detect_zstd_checks_whole_magic_number() {
+        assert_eq!(Some(Compression::Zstd), Compression::detect(&[0x28, 0xb5, 0x2f, 0xfd, 0x00]));
+        assert_eq!(None, Compression::detect(&[0x28, 0xb5, 0x00, 0x00]));
+        assert_eq!(None, Compression::detect(&[0x28, 0xb5]));
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn read_message_unchunked_zstd() {
+        let mut gelf = Gelf::new(Default::default());
+
+        let gelf_msg = br#"{"version":"1.1","host":"example.org","short_message":"Hello!"}"#;
+        let compressed = zstd::stream::encode_all(&gelf_msg[..], 0).expect("failed to encode bytes");
+
+        let mut msg = gelf
+            .decode(compressed.into())
+            .expect("failed to decode message")
+            .expect("missing message value")
+            .into_reader()
+            .expect("failed to build reader");
+
+        let mut read = String::new();
+        msg.read_to_string(&mut read)
+            .expect("failed to read message");
+
+        assert_eq!(&gelf_msg[..], read.as_bytes());
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn read_message_zstd_decompressed_too_large_fails() {
+        let mut gelf = Gelf::new(Config {
+            max_decompressed_bytes: 5,
+            ..Default::default()
+        });
+
+        let compressed = zstd::stream::encode_all(&b"Hello!"[..], 0).expect("failed to encode bytes");
+
+        let mut msg = gelf
+            .decode(compressed.into())
+            .expect("failed to decode message")
+            .expect("missing message value")
+            .into_reader()
+            .expect("failed to build reader");
+
+        assert!(msg.read_to_string(&mut String::new()).is_err());
+    }
+
+    #[test]
+    fn read_message_decompressed_too_large_fails() {
+        let mut gelf = Gelf::new(Config {
+            max_decompressed_bytes: 5,
+            ..Default::default()
+        });
+
+        let mut gzip_msg = gelf
+            .decode(gzip(b"Hello!"))
+            .expect("failed to decode message")
+            .expect("missing message value")
+            .into_reader()
+            .expect("failed to build reader");
+
+        let mut zlib_msg = gelf
+            .decode(zlib(b"Hello!"))
+            .expect("failed to decode message")
+            .expect("missing message value")
+            .into_reader()
+            .expect("failed to build reader");
+
+        assert!(gzip_msg.read_to_string(&mut String::new()).is_err());
+        assert!(zlib_msg.read_to_string(&mut String::new()).is_err());
+    }
+
     #[test]
     fn message_single_chunk() {
         let mut gelf = Gelf::new(Default::default());
@@ -661,12 +930,12 @@ mod tests {
             .expect("failed to decode message")
             .expect("missing message value");
 
-        let expected = Message(MessageInner::Single {
+        let expected = MessageInner::Single {
             compression: None,
             bytes: Bytes::from(b"Hello!" as &[u8]),
-        });
+        };
 
-        assert_eq!(expected, msg);
+        assert_eq!(expected, msg.inner);
     }
 
     #[test]
@@ -701,15 +970,100 @@ mod tests {
             .expect("failed to decode message")
             .expect("missing message value");
 
-        let expected = Message(MessageInner::Chunked {
+        let expected = MessageInner::Chunked {
             chunks: vec![
                 Bytes::from(b"Hello" as &[u8]),
                 Bytes::from(b" World" as &[u8]),
                 Bytes::from(b"!" as &[u8]),
             ],
-        });
+        };
+
+        assert_eq!(expected, msg.inner);
+    }
+
+    #[test]
+    fn message_multiple_chunks_interleaved_duplicates() {
+        let mut gelf = Gelf::new(Default::default());
+
+        let chunks = [
+            chunk(0, 0, 3, b"Hello"),
+            chunk(1, 0, 2, b"Goodbye"),
+            chunk(0, 0, 3, b"Duplicate"),
+            chunk(1, 0, 2, b"Duplicate"),
+            chunk(0, 1, 3, b" World"),
+            chunk(0, 1, 3, b"Duplicate"),
+        ];
+
+        for chunk in chunks {
+            let partial = gelf.decode(chunk).expect("failed to decode message");
+
+            assert!(partial.is_none());
+        }
+
+        let msg = gelf
+            .decode(chunk(1, 1, 2, b" World"))
+            .expect("failed to decode message")
+            .expect("missing message value");
+
+        let expected = MessageInner::Chunked {
+            chunks: vec![
+                Bytes::from(b"Goodbye" as &[u8]),
+                Bytes::from(b" World" as &[u8]),
+            ],
+        };
+
+        assert_eq!(expected, msg.inner);
 
-        assert_eq!(expected, msg);
+        let msg = gelf
+            .decode(chunk(0, 2, 3, b"!"))
+            .expect("failed to decode message")
+            .expect("missing message value");
+
+        let expected = MessageInner::Chunked {
+            chunks: vec![
+                Bytes::from(b"Hello" as &[u8]),
+                Bytes::from(b" World" as &[u8]),
+                Bytes::from(b"!" as &[u8]),
+            ],
+        };
+
+        assert_eq!(expected, msg.inner);
+    }
+
+    #[test]
+    fn message_multiple_chunks_out_of_order_then_duplicate() {
+        let mut gelf = Gelf::new(Default::default());
+
+        let before = METRICS.chunk_duplicate.get();
+
+        let chunks = [
+            chunk(0, 2, 3, b"!"),
+            chunk(0, 0, 3, b"Hello"),
+            chunk(0, 2, 3, b"?"),
+        ];
+
+        for chunk in chunks {
+            let partial = gelf.decode(chunk).expect("failed to decode message");
+
+            assert!(partial.is_none());
+        }
+
+        assert!(METRICS.chunk_duplicate.get() > before);
+
+        let msg = gelf
+            .decode(chunk(0, 1, 3, b" World"))
+            .expect("failed to decode message")
+            .expect("missing message value");
+
+        let expected = MessageInner::Chunked {
+            chunks: vec![
+                Bytes::from(b"Hello" as &[u8]),
+                Bytes::from(b" World" as &[u8]),
+                Bytes::from(b"!" as &[u8]),
+            ],
+        };
+
+        assert_eq!(expected, msg.inner);
     }
 
     #[test]
@@ -818,6 +1172,36 @@ mod tests {
         assert_eq!(2, *gelf.by_id.chunks.keys().next().unwrap());
     }
 
+    #[test]
+    fn when_max_inflight_is_reached_oldest_incomplete_messages_are_evicted() {
+        let mut gelf = Gelf::new(Config {
+            chunk_max_inflight: Some(8),
+            ..Default::default()
+        });
+
+        let before = METRICS.chunk_evicted.get();
+
+        for id in 0..100 {
+            gelf.decode(chunk(id, 0, 2, b"1"))
+                .expect("failed to decode message");
+
+            assert!(gelf.by_id.chunks.len() <= 8);
+            assert_eq!(gelf.by_id.chunks.len(), gelf.by_arrival.chunks.len());
+        }
+
+        assert!(METRICS.chunk_evicted.get() >= before + 92);
+
+        // The most recent messages are still tracked, so they can be completed
+        let mut ids: Vec<_> = gelf.by_id.chunks.keys().copied().collect();
+        ids.sort_unstable();
+        assert_eq!((92..100).collect::<Vec<_>>(), ids);
+
+        assert!(gelf
+            .decode(chunk(99, 1, 2, b"2"))
+            .expect("failed to decode message")
+            .is_some());
+    }
+
     #[test]
     fn when_timeout_expires_incomplete_messages_are_dropped() {
         let mut gelf = Gelf::new(Config {
@@ -854,7 +1238,7 @@ mod tests {
         // chunk is allowed
         let r = gelf.decode(chunk(0, 0, 3, b"1"));
 
-        assert!(r.is_err());
+        assert_eq!(Some(ErrorKind::Reassembly), r.err().map(|e| e.kind()));
     }
 
     #[test]
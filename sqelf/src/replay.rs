@@ -0,0 +1,306 @@
+/*!
+Replaying captured GELF payloads through the receiver and processor.
+
+This doesn't bind any sockets. Payloads are read from a file and passed
+to the same `receive` and `process` functions the server would use, so a
+problematic sample of traffic can be captured once and then reproduced.
+*/
+
+use std::{fmt, fs, path::PathBuf, str::FromStr, thread, time::Duration};
+
+use bytes::Bytes;
+
+use crate::{
+    diagnostics::{emit_debug, emit_err},
+    error::{err_msg, Error},
+    receive::Message,
+    server::increment_kind,
+};
+
+/**
+Configuration for replaying captured payloads.
+*/
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /**
+    The file to replay payloads from.
+
+    If this is set then payloads are replayed instead of running the server.
+    */
+    pub path: Option<PathBuf>,
+    /**
+    How payloads are separated in the file.
+    */
+    pub framing: Framing,
+    /**
+    The number of payloads to replay each second.
+
+    If this is unset then payloads are replayed as fast as they can be processed.
+    */
+    pub rate_per_sec: Option<f64>,
+    /**
+    Whether to start again from the beginning of the file after the last payload.
+
+    When this is set, replaying never completes.
+    */
+    pub repeat: bool,
+}
+
+/**
+How payloads are separated in a replay file.
+*/
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    /**
+    Each payload is followed by a null byte.
+
+    The last payload doesn't need to be followed by a null byte.
+    */
+    #[default]
+    NullDelimited,
+    /**
+    Each payload is preceded by its length as a 4 byte big-endian integer.
+
+    This suits compressed or chunked payloads, which can contain null bytes.
+    */
+    LengthPrefixed,
+}
+
+impl FromStr for Framing {
+    type Err = ParseFramingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_ascii_lowercase() {
+            "null_delimited" => Ok(Framing::NullDelimited),
+            "length_prefixed" => Ok(Framing::LengthPrefixed),
+            _ => Err(ParseFramingError(s.to_owned())),
+        }
+    }
+}
+
+/**
+A replay framing that isn't supported.
+*/
+#[derive(Debug)]
+pub struct ParseFramingError(String);
+
+impl fmt::Display for ParseFramingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` isn't a supported replay framing; expected `null_delimited` or `length_prefixed`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseFramingError {}
+
+/**
+Replay the payloads in the configured file.
+
+Each payload is passed to `receive`, and any complete messages are passed to
+`process`. Failures for individual payloads are reported the same way the
+server reports them, and don't stop the replay. Returns the number of
+messages that were processed.
+*/
+pub fn replay(
+    config: &Config,
+    mut receive: impl FnMut(Bytes) -> Result<Option<Message>, Error>,
+    mut process: impl FnMut(Message) -> Result<(), Error>,
+) -> Result<usize, Error> {
+    let path = config
+        .path
+        .as_ref()
+        .ok_or_else(|| err_msg("a replay file path is required"))?;
+
+    let contents = fs::read(path).map_err(|e| {
+        err_msg(format_args!("failed to read replay file `{}`: {}", path.display(), e))
+    })?;
+
+    let payloads = frames(Bytes::from(contents), config.framing)?;
+
+    let interval = match config.rate_per_sec {
+        Some(rate) if rate.is_finite() && rate > 0.0 => Some(Duration::from_secs_f64(1.0 / rate)),
+        Some(rate) => bail!("`{}` isn't a valid replay rate; expected a positive number", rate),
+        None => None,
+    };
+
+    emit_debug(
+        "Replaying {Payloads} GELF payloads from {Path}",
+        serde_json::json!({ "Payloads": payloads.len(), "Path": path }),
+    );
+
+    let mut processed = 0;
+
+    loop {
+        for payload in &payloads {
+            match receive(payload.clone()) {
+                Ok(Some(msg)) => match process(msg) {
+                    Ok(()) => processed += 1,
+                    Err(err) => {
+                        increment!(process_err);
+                        increment_kind(&err);
+                        emit_err(&err, "GELF processing failed");
+                    }
+                },
+                Ok(None) => (),
+                Err(err) => {
+                    increment!(receive_err);
+                    increment_kind(&err);
+                    emit_err(&err, "GELF receive failed");
+                }
+            }
+
+            if let Some(interval) = interval {
+                thread::sleep(interval);
+            }
+        }
+
+        if !config.repeat || payloads.is_empty() {
+            return Ok(processed);
+        }
+    }
+}
+
+/**
+Split the contents of a replay file into payloads.
+
+Empty payloads are skipped.
+*/
+fn frames(mut contents: Bytes, framing: Framing) -> Result<Vec<Bytes>, Error> {
+    let mut payloads = Vec::new();
+
+    match framing {
+        Framing::NullDelimited => {
+            while !contents.is_empty() {
+                let end = contents.iter().position(|b| *b == 0).unwrap_or(contents.len());
+
+                let payload = contents.split_to(end);
+                if !contents.is_empty() {
+                    contents.advance(1);
+                }
+
+                if !payload.is_empty() {
+                    payloads.push(payload);
+                }
+            }
+        }
+        Framing::LengthPrefixed => {
+            while !contents.is_empty() {
+                if contents.len() < 4 {
+                    bail!("the replay file ends partway through a length prefix");
+                }
+
+                let prefix = contents.split_to(4);
+                let len = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+
+                if contents.len() < len {
+                    bail!(
+                        "the replay file ends partway through a payload; expected {} bytes but found {}",
+                        len,
+                        contents.len()
+                    );
+                }
+
+                let payload = contents.split_to(len);
+
+                if !payload.is_empty() {
+                    payloads.push(payload);
+                }
+            }
+        }
+    }
+
+    Ok(payloads)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    use crate::{output::OutputSink, process, receive};
+
+    #[test]
+    fn parse_framing() {
+        assert_eq!(Framing::NullDelimited, "null_delimited".parse().unwrap());
+        assert_eq!(Framing::LengthPrefixed, "Length_Prefixed".parse().unwrap());
+        assert!("lines".parse::<Framing>().is_err());
+    }
+
+    #[test]
+    fn split_null_delimited() {
+        let payloads = frames(Bytes::from_static(b"{\"a\":1}\0\0{\"b\":2}"), Framing::NullDelimited)
+            .expect("failed to split payloads");
+
+        assert_eq!(
+            vec![Bytes::from_static(b"{\"a\":1}"), Bytes::from_static(b"{\"b\":2}")],
+            payloads
+        );
+    }
+
+    #[test]
+    fn split_length_prefixed() {
+        let payloads = frames(
+            Bytes::from_static(b"\0\0\0\x03a\0b\0\0\0\0\0\0\0\x01c"),
+            Framing::LengthPrefixed,
+        )
+        .expect("failed to split payloads");
+
+        assert_eq!(vec![Bytes::from_static(b"a\0b"), Bytes::from_static(b"c")], payloads);
+
+        assert!(frames(Bytes::from_static(b"\0\0\0\x05abc"), Framing::LengthPrefixed).is_err());
+        assert!(frames(Bytes::from_static(b"\0\0"), Framing::LengthPrefixed).is_err());
+    }
+
+    #[test]
+    fn replay_through_receive_and_process() {
+        let path = env::temp_dir().join(format!("sqelf-replay-{}", std::process::id()));
+
+        let msg = |n: usize| {
+            serde_json::json!({
+                "version": "1.1",
+                "host": "example.org",
+                "short_message": format!("Message {}", n),
+            })
+            .to_string()
+        };
+
+        fs::write(&path, format!("{}\0not gelf\0{}", msg(1), msg(2))).unwrap();
+
+        struct Events(Vec<String>);
+
+        impl OutputSink for Events {
+            fn send(&mut self, event: &str) -> Result<(), Error> {
+                self.0.push(event.to_owned());
+
+                Ok(())
+            }
+        }
+
+        let mut gelf = receive::build(Default::default());
+        let clef = process::build(Default::default());
+        let mut events = Events(Vec::new());
+
+        let processed = replay(
+            &Config {
+                path: Some(path.clone()),
+                ..Default::default()
+            },
+            |src| gelf.decode(src),
+            |msg| clef.read_as_clef(msg, &mut events),
+        )
+        .expect("failed to replay payloads");
+
+        // The payload that isn't GELF fails to process, but doesn't stop the replay
+        assert_eq!(2, processed);
+        assert_eq!(2, events.0.len());
+        assert!(events.0[1].contains("Message 2"));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+/*!
+Probabilistic sampling of received messages.
+*/
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/**
+A sampler that keeps a fraction of messages.
+
+Sampling uses a small deterministic generator so that a fixed seed
+always keeps the same messages.
+*/
+pub(crate) struct Sampler {
+    rate: f64,
+    state: u64,
+}
+
+impl Sampler {
+    /**
+    Create a sampler that keeps messages with the given probability.
+
+    If `seed` is `None` then the sampler is seeded from the current time.
+    */
+    pub(crate) fn new(rate: f64, seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_nanos() as u64)
+                .unwrap_or_default()
+        });
+
+        Sampler {
+            rate: if rate.is_nan() { 1.0 } else { rate.clamp(0.0, 1.0) },
+            state: seed,
+        }
+    }
+
+    /**
+    Check whether the next message should be kept.
+    */
+    pub(crate) fn keep(&mut self) -> bool {
+        if self.rate >= 1.0 {
+            return true;
+        }
+
+        if self.rate <= 0.0 {
+            return false;
+        }
+
+        self.next_f64() < self.rate
+    }
+
+    // A `splitmix64` generator, mapped onto `[0, 1)`
+    fn next_f64(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_samplers_are_deterministic() {
+        let mut a = Sampler::new(0.5, Some(42));
+        let mut b = Sampler::new(0.5, Some(42));
+
+        let a: Vec<_> = (0..100).map(|_| a.keep()).collect();
+        let b: Vec<_> = (0..100).map(|_| b.keep()).collect();
+
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn keeps_roughly_the_sample_rate() {
+        let mut sampler = Sampler::new(0.25, Some(7));
+
+        let kept = (0..10_000).filter(|_| sampler.keep()).count();
+
+        assert!((2_250..2_750).contains(&kept), "kept {} of 10000", kept);
+
+        assert!((0..100).all(|_| Sampler::new(1.0, Some(7)).keep()));
+        assert!((0..100).all(|_| !Sampler::new(0.0, Some(7)).keep()));
+    }
+}
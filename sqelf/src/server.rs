@@ -1,37 +1,198 @@
-use std::{net::SocketAddr, thread};
+use std::{
+    cmp,
+    collections::BTreeSet,
+    fmt,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
 use tokio::{
-    codec::Decoder,
-    net::udp::{UdpFramed, UdpSocket},
+    net::{udp::UdpSocket, TcpListener},
     prelude::*,
+    reactor,
+    timer::Interval,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use serde::{
+    de::{self, Deserialize, Deserializer},
+    ser::{Serialize, Serializer},
 };
 
+use serde_json::json;
+
+#[cfg(unix)]
+use tokio::net::unix::UnixDatagram;
+
+#[cfg(unix)]
+use tokio::codec::Decoder;
+
+#[cfg(unix)]
+use tokio_uds::UnixDatagramFramed;
+
 use bytes::{Bytes, BytesMut};
 
-use futures::{future::lazy, sync::mpsc, future::Either};
+use futures::{sync::{mpsc, oneshot}, future::Either, stream};
 
 use crate::{
-    error::Error,
+    audit::Audit,
+    error::{Error, ErrorKind, err_msg},
     diagnostics::*,
+    http,
+    queue::{self, OverflowPolicy},
+    rate_limit::{self, RateLimit},
     receive::Message,
+    sample::Sampler,
 };
 
 /**
 Server configuration.
 */
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     /**
-    The address to bind the UDP server to.
+    The addresses to bind the server to.
     */
-    pub bind: String,
+    pub bind: Binds,
     /**
     The maximum number of unprocessed messages.
 
-    If this value is reached then incoming messages will be dropped.
+    If this value is reached then `queue_overflow_policy` determines
+    what happens to incoming messages.
     */
     pub unprocessed_capacity: usize,
 
+    /**
+    What to do with incoming messages when `unprocessed_capacity` is reached.
+
+    By default, incoming messages are dropped. For UDP, waiting for space
+    would just cause the OS to drop datagrams from the socket instead.
+    */
+    pub queue_overflow_policy: OverflowPolicy,
+
+    /**
+    The size in bytes to request for the receive buffer of UDP sockets.
+
+    If this value is `None` then the OS default is used. The OS may clamp
+    the requested size, in which case a warning is emitted with the size
+    that was actually used.
+    */
+    pub udp_recv_buffer_bytes: Option<usize>,
+
+    /**
+    Whether or not to set `SO_REUSEPORT` and `SO_REUSEADDR` on UDP sockets.
+
+    This lets multiple processes bind the same address, and the kernel will
+    balance datagrams across them. Chunks of a chunked message may end up in
+    different processes though, so chunked messages are unlikely to be reassembled.
+
+    `SO_REUSEPORT` is only supported on Unix platforms. On Linux, every process
+    bound to the address must be running as the same user. On other platforms,
+    a warning is emitted and the socket is bound without it.
+    */
+    pub udp_reuse_port: bool,
+
+    /**
+    Whether or not UDP sockets bound to IPv6 addresses also receive IPv4 datagrams.
+
+    When this value is `true`, `IPV6_V6ONLY` is cleared so a socket bound to
+    `[::]` will receive datagrams from IPv4 clients as IPv4-mapped addresses.
+    */
+    pub dual_stack: bool,
+
+    /**
+    The number of datagrams per second to accept from a single source address on UDP sockets.
+
+    Datagrams from a source that exceed this rate are dropped. If this value
+    is `None` then datagrams aren't rate limited.
+    */
+    pub udp_rate_limit_per_sec: Option<u32>,
+
+    /**
+    The number of datagrams a single source can send at once before being rate limited.
+
+    If this value is `None` then it's the same as `udp_rate_limit_per_sec`.
+    */
+    pub udp_rate_limit_burst: Option<u32>,
+
+    /**
+    The maximum number of source addresses to track for rate limiting.
+
+    If this value is reached then the least recently seen source is forgotten.
+    */
+    pub udp_rate_limit_sources: usize,
+
+    /**
+    The maximum size in bytes of the body of a GELF message POSTed over HTTP.
+
+    Requests with larger bodies are rejected with `413 Payload Too Large`.
+    This can be overridden for a single address with `?max=`.
+    */
+    pub http_max_body_bytes: usize,
+
+    /**
+    Whether or not to split UDP datagrams into multiple messages on null bytes.
+
+    Some senders pack several null-delimited GELF messages into a single datagram.
+    Only datagrams that look like uncompressed JSON are split, so chunked and
+    compressed datagrams are always received whole.
+    */
+    pub udp_split_on_null: bool,
+
+    /**
+    The maximum size in bytes of a UDP datagram.
+
+    Larger datagrams are dropped before they're decoded, so oversized
+    or maliciously compressed payloads are cheap to reject.
+    This can be overridden for a single address with `?max=`.
+    */
+    pub udp_max_size_bytes: usize,
+
+    /**
+    The path of a file to record the raw payload of every received message in.
+
+    Payloads are recorded before they're rate limited, decompressed, or parsed,
+    along with the address they were received from. Recording happens in the
+    background, so it never slows down receiving. If this value is `None` then
+    payloads aren't recorded.
+    */
+    pub audit_path: Option<PathBuf>,
+
+    /**
+    The maximum number of payloads waiting to be written to the audit log.
+
+    If this value is reached then payloads aren't recorded until the audit
+    log catches up.
+    */
+    pub audit_capacity: usize,
+
+    /**
+    The fraction of received messages to process, between `0.0` and `1.0`.
+
+    Messages are dropped at random so the given fraction are processed.
+    This reduces volume proportionally, unlike rate limiting which caps
+    each source. If this value is `1.0` then all messages are processed.
+    */
+    pub sample_rate: f64,
+
+    /**
+    The seed to use for sampling messages.
+
+    A fixed seed always samples the same messages. If this value is
+    `None` then sampling is seeded from the current time.
+    */
+    pub sample_seed: Option<u64>,
+
     /**
     Whether or not the server should wait on (and terminate on the completion of)
     the process's standard input.
@@ -39,144 +200,1669 @@ pub struct Config {
     This is used by Seq on Windows to signal to a
     background process - which has no window to receive WM_CLOSE, and no console
     to receive Ctrl+C, that the process should exit.
+
+    This value is determined by the environment, so it can't be set in a config file.
     */
+    #[serde(skip_deserializing)]
     pub wait_on_stdin: bool,
+
+    /**
+    The time in milliseconds to wait for unprocessed messages when shutting down.
+
+    When the server shuts down it stops receiving new messages, but will
+    continue processing messages it has already received. If this period
+    elapses before they're all processed then the remaining messages are
+    abandoned.
+    */
+    pub shutdown_grace_ms: u64,
+
+    /**
+    The interval in milliseconds between emitting server metrics.
+
+    Metrics are emitted as diagnostic events. If this value is `0`
+    then metrics aren't emitted.
+    */
+    pub metrics_interval_ms: u64,
+
+    /**
+    The window in milliseconds to calculate the rate of received and processed messages over.
+
+    Rates are emitted and served alongside other metrics. If this value is `0`
+    then rates aren't calculated.
+    */
+    pub metrics_rate_window_ms: u64,
+
+    /**
+    The address to bind an HTTP server for metrics to.
+
+    Metrics are served from `/metrics` in the Prometheus text format.
+    If this value is `None` then metrics aren't served over HTTP.
+    */
+    pub metrics_bind: Option<String>,
+
+    /**
+    The address to bind an HTTP server for health checks to.
+
+    Health checks are served from `/health`, and return `200 OK` once the
+    server has been bound and `503 Service Unavailable` otherwise.
+    If this value is the same as `metrics_bind`, or is `None`, then health
+    checks are served alongside metrics.
+    */
+    pub health_bind: Option<String>,
+
+    /**
+    Whether or not to send a synthetic GELF message through the receiver
+    and processor before the server starts.
+
+    The message is handled like any other, so it'll appear in the output
+    with a `selftest` property. This catches a misconfigured pipeline
+    before any real messages are lost to it.
+    */
+    pub selftest: bool,
+
+    /**
+    Whether or not a failed self-test stops the server from starting.
+
+    If this value is `false` then a failed self-test is only warned about.
+    */
+    pub selftest_fatal: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
-            bind: "0.0.0.0:12201".to_owned(),
+            bind: Binds(vec![Bind::Udp("0.0.0.0:12201".to_owned(), None)]),
             unprocessed_capacity: 1024,
+            queue_overflow_policy: OverflowPolicy::default(),
+            udp_recv_buffer_bytes: None,
+            udp_reuse_port: false,
+            dual_stack: true,
+            udp_rate_limit_per_sec: None,
+            udp_rate_limit_burst: None,
+            udp_rate_limit_sources: 4096,
+            http_max_body_bytes: 1024 * 1024,
+            udp_split_on_null: false,
+            udp_max_size_bytes: 64 * 1024,
+            audit_path: None,
+            audit_capacity: 1024,
+            sample_rate: 1.0,
+            sample_seed: None,
             wait_on_stdin: false,
+            shutdown_grace_ms: 5 * 1000,
+            metrics_interval_ms: 60 * 1000,
+            metrics_rate_window_ms: 10 * 1000,
+            metrics_bind: None,
+            health_bind: None,
+            selftest: false,
+            selftest_fatal: true,
+        }
+    }
+}
+
+impl Config {
+    /**
+    Begin building a server configuration.
+
+    Any values that aren't set use their defaults.
+    */
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config::default(),
         }
     }
 }
 
 /**
-Build a server to receive GELF messages and process them.
+A builder for server configuration.
 */
-pub fn build(
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
     config: Config,
-    receive: impl FnMut(Bytes) -> Result<Option<Message>, Error> + Send + Sync + 'static,
-    mut handle: impl FnMut(Message) -> Result<(), Error> + Send + Sync + 'static,
-) -> Result<impl Future<Item = (), Error = Exit>, Error> {
-    let addr: SocketAddr = config.bind.parse()?;
-    let sock = UdpSocket::bind(&addr)?;
+}
 
-    let (tx, rx) = mpsc::channel(config.unprocessed_capacity);
+impl ConfigBuilder {
+    /**
+    Set the addresses to bind the server to.
+    */
+    pub fn bind(mut self, bind: Binds) -> Self {
+        self.config.bind = bind;
+        self
+    }
 
-    // Attempt to bind shutdown signals
-    let shutdown = tokio_signal::ctrl_c().map_err(emit_abort_with("Server setup failed", exit_failure));
+    /**
+    Set the maximum number of unprocessed messages.
+    */
+    pub fn unprocessed_capacity(mut self, unprocessed_capacity: usize) -> Self {
+        self.config.unprocessed_capacity = unprocessed_capacity;
+        self
+    }
 
-    Ok(shutdown.and_then(move |shutdown| {
-        // Spawn a background task to process GELF payloads
-        let process = tokio::spawn(lazy(move || {
-            rx.for_each(move |msg| handle(msg).or_else(emit_continue("GELF processing failed")))
-        }));
+    /**
+    Set what to do with incoming messages when `unprocessed_capacity` is reached.
+    */
+    pub fn queue_overflow_policy(mut self, queue_overflow_policy: OverflowPolicy) -> Self {
+        self.config.queue_overflow_policy = queue_overflow_policy;
+        self
+    }
 
-        // Spawn a background task to poll `stdio`
-        let stdin_closed = if config.wait_on_stdin {
-            Either::A(stdin_closed()
-                .map(|_| Op::Shutdown))
-        } else {
-            Either::B(future::empty())
-        }.into_stream();
+    /**
+    Set the size in bytes to request for the receive buffer of UDP sockets.
+    */
+    pub fn udp_recv_buffer_bytes(mut self, udp_recv_buffer_bytes: usize) -> Self {
+        self.config.udp_recv_buffer_bytes = Some(udp_recv_buffer_bytes);
+        self
+    }
 
-        // Listen for Ctrl + C and other termination signals
-        // from the OS
-        let shutdown = shutdown
-            .map(|_| Op::Shutdown)
-            .map_err(emit_abort("Server shutdown was unclean"));
+    /**
+    Set whether or not to set `SO_REUSEPORT` and `SO_REUSEADDR` on UDP sockets.
+    */
+    pub fn udp_reuse_port(mut self, udp_reuse_port: bool) -> Self {
+        self.config.udp_reuse_port = udp_reuse_port;
+        self
+    }
 
-        // Accept and process incoming GELF messages over UDP
-        // This stream should never return an `Err` variant
-        let server = UdpFramed::new(sock, Decode(receive))
-            .map(|(msg, _)| Op::Receive(Some(msg)))
-            .or_else(emit_continue_with("GELF receive failed", receive_empty));
+    /**
+    Set whether or not UDP sockets bound to IPv6 addresses also receive IPv4 datagrams.
+    */
+    pub fn dual_stack(mut self, dual_stack: bool) -> Self {
+        self.config.dual_stack = dual_stack;
+        self
+    }
 
-        server
-            .select(shutdown)
-            .select(stdin_closed)
-            .and_then(|msg| match msg {
-                // Continue processing received messages
-                Op::Receive(msg) => Ok(msg),
-                // Terminate on shutdown messages
-                // The error here causes the future to return
-                Op::Shutdown => {
-                    emit("Termination signal received; shutting down");
+    /**
+    Set the number of datagrams per second to accept from a single source address.
+    */
+    pub fn udp_rate_limit_per_sec(mut self, udp_rate_limit_per_sec: u32) -> Self {
+        self.config.udp_rate_limit_per_sec = Some(udp_rate_limit_per_sec);
+        self
+    }
 
-                    Err(())
-                }
-            })
-            // Process messages
-            .filter_map(|msg| msg)
-            .for_each(move |msg| {
-                let tx = tx.clone();
-                tx.send(msg)
-                    .map(|_| ())
-                    .or_else(emit_continue("GELF buffering failed"))
-            })
-            // If we get this far then the server is shutting down
-            // Wait for the message pipeline to terminate
-            .then(|_| process)
-            // FIXME: Forces the runtime to shutdown
-            // This is a bit of a hack that prevents
-            // `tokio` from waiting on any remaining futures
-            // since we're terminating the process
-            .then(|r| match r {
-                Ok(()) => Err(Exit::Clean),
-                Err(()) => Err(Exit::Failure),
-            })
-    }))
-}
+    /**
+    Set the number of datagrams a single source can send at once before being rate limited.
+    */
+    pub fn udp_rate_limit_burst(mut self, udp_rate_limit_burst: u32) -> Self {
+        self.config.udp_rate_limit_burst = Some(udp_rate_limit_burst);
+        self
+    }
 
-/**
-The outcome of shutting down the server.
-*/
-pub enum Exit {
     /**
-    The server was terminated, but was done so cleanly.
+    Set the maximum number of source addresses to track for rate limiting.
     */
-    Clean,
+    pub fn udp_rate_limit_sources(mut self, udp_rate_limit_sources: usize) -> Self {
+        self.config.udp_rate_limit_sources = udp_rate_limit_sources;
+        self
+    }
+
     /**
-    The server was terminated, but due to an internal error.
+    Set the maximum size in bytes of the body of a GELF message POSTed over HTTP.
     */
-    Failure,
-}
+    pub fn http_max_body_bytes(mut self, http_max_body_bytes: usize) -> Self {
+        self.config.http_max_body_bytes = http_max_body_bytes;
+        self
+    }
 
-fn exit_failure() -> Exit {
-    Exit::Failure
+    /**
+    Set whether or not to split UDP datagrams into multiple messages on null bytes.
+    */
+    pub fn udp_split_on_null(mut self, udp_split_on_null: bool) -> Self {
+        self.config.udp_split_on_null = udp_split_on_null;
+        self
+    }
+
+    /**
+    Set the maximum size in bytes of a UDP datagram.
+    */
+    pub fn udp_max_size_bytes(mut self, udp_max_size_bytes: usize) -> Self {
+        self.config.udp_max_size_bytes = udp_max_size_bytes;
+        self
+    }
+
+    /**
+    Set the path of a file to record the raw payload of every received message in.
+    */
+    pub fn audit_path(mut self, audit_path: impl Into<PathBuf>) -> Self {
+        self.config.audit_path = Some(audit_path.into());
+        self
+    }
+
+    /**
+    Set the maximum number of payloads waiting to be written to the audit log.
+    */
+    pub fn audit_capacity(mut self, audit_capacity: usize) -> Self {
+        self.config.audit_capacity = audit_capacity;
+        self
+    }
+
+    /**
+    Set the fraction of received messages to process.
+    */
+    pub fn sample_rate(mut self, sample_rate: f64) -> Self {
+        self.config.sample_rate = sample_rate;
+        self
+    }
+
+    /**
+    Set the seed to use for sampling messages.
+    */
+    pub fn sample_seed(mut self, sample_seed: u64) -> Self {
+        self.config.sample_seed = Some(sample_seed);
+        self
+    }
+
+    /**
+    Set whether or not the server should wait on the process's standard input.
+    */
+    pub fn wait_on_stdin(mut self, wait_on_stdin: bool) -> Self {
+        self.config.wait_on_stdin = wait_on_stdin;
+        self
+    }
+
+    /**
+    Set the time in milliseconds to wait for unprocessed messages when shutting down.
+    */
+    pub fn shutdown_grace_ms(mut self, shutdown_grace_ms: u64) -> Self {
+        self.config.shutdown_grace_ms = shutdown_grace_ms;
+        self
+    }
+
+    /**
+    Set the interval in milliseconds between emitting server metrics.
+    */
+    pub fn metrics_interval_ms(mut self, metrics_interval_ms: u64) -> Self {
+        self.config.metrics_interval_ms = metrics_interval_ms;
+        self
+    }
+
+    /**
+    Set the window in milliseconds to calculate message rates over.
+    */
+    pub fn metrics_rate_window_ms(mut self, metrics_rate_window_ms: u64) -> Self {
+        self.config.metrics_rate_window_ms = metrics_rate_window_ms;
+        self
+    }
+
+    /**
+    Set the address to bind an HTTP server for metrics to.
+    */
+    pub fn metrics_bind(mut self, metrics_bind: impl Into<String>) -> Self {
+        self.config.metrics_bind = Some(metrics_bind.into());
+        self
+    }
+
+    /**
+    Set the address to bind an HTTP server for health checks to.
+    */
+    pub fn health_bind(mut self, health_bind: impl Into<String>) -> Self {
+        self.config.health_bind = Some(health_bind.into());
+        self
+    }
+
+    /**
+    Set whether or not to send a synthetic message through the pipeline before starting.
+    */
+    pub fn selftest(mut self, selftest: bool) -> Self {
+        self.config.selftest = selftest;
+        self
+    }
+
+    /**
+    Set whether or not a failed self-test stops the server from starting.
+    */
+    pub fn selftest_fatal(mut self, selftest_fatal: bool) -> Self {
+        self.config.selftest_fatal = selftest_fatal;
+        self
+    }
+
+    /**
+    Build the server configuration.
+    */
+    pub fn build(self) -> Config {
+        self.config
+    }
 }
 
-struct Decode<F>(F);
+/**
+A set of addresses to bind the server to.
 
-impl<F> Decoder for Decode<F>
-where
-    F: FnMut(Bytes) -> Result<Option<Message>, Error>,
-{
-    type Item = Message;
-    type Error = Error;
+When parsed, addresses are separated by commas, like `0.0.0.0:12201,[::]:12202`.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binds(pub Vec<Bind>);
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let src = src.take().freeze();
+impl FromStr for Binds {
+    type Err = ParseBindError;
 
-        (self.0)(src)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(Bind::from_str)
+            .collect::<Result<_, _>>()
+            .map(Binds)
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum Op {
-    Receive(Option<Message>),
-    Shutdown,
+impl Serialize for Binds {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let binds: Vec<_> = self.0.iter().map(ToString::to_string).collect();
+
+        serializer.collect_str(&binds.join(","))
+    }
 }
 
-fn receive_empty() -> Op {
-    Op::Receive(None)
+impl<'de> Deserialize<'de> for Binds {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let binds = String::deserialize(deserializer)?;
+
+        binds.parse().map_err(de::Error::custom)
+    }
 }
 
-fn stdin_closed() -> impl Future<Item = (), Error = ()> {
-    let (tx, rx) = mpsc::channel(1);
+/**
+An address to bind the server to.
+
+UDP and HTTP addresses can set their own maximum message size in bytes,
+like `udp://0.0.0.0:12201?max=8k`. Sizes can use the suffixes `k`, `m`,
+or `g`, which are powers of `1024`. Addresses without a maximum use the
+protocol's default from the server configuration.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bind {
+    /**
+    A UDP socket address, like `0.0.0.0:12201` or `udp://0.0.0.0:12201`.
+
+    The maximum size overrides `udp_max_size_bytes`.
+    */
+    Udp(String, Option<usize>),
+    /**
+    A TCP socket address to receive GELF messages POSTed over HTTP to `/gelf`,
+    like `http://0.0.0.0:12202`.
+
+    The maximum size overrides `http_max_body_bytes`.
+    */
+    Http(String, Option<usize>),
+    /**
+    The path to a Unix domain datagram socket, like `unix:///var/run/sqelf.sock`.
+
+    Unix domain sockets are only supported on Unix platforms.
+    */
+    Unix(PathBuf),
+}
+
+impl Bind {
+    const UDP_SCHEME: &'static str = "udp://";
+    const HTTP_SCHEME: &'static str = "http://";
+    const UNIX_SCHEME: &'static str = "unix://";
+
+    const SCHEME_SEPARATOR: &'static str = "://";
+    const MAX_PARAM: &'static str = "max=";
+
+    /**
+    Split an address like `0.0.0.0:12201?max=8k` into its address and maximum size.
+    */
+    fn parse_max(addr: &str) -> Option<(&str, Option<usize>)> {
+        let (addr, query) = match addr.find('?') {
+            Some(start) => (&addr[..start], &addr[start + 1..]),
+            None => return Some((addr, None)),
+        };
+
+        let size = query.strip_prefix(Self::MAX_PARAM)?.trim().to_ascii_lowercase();
+
+        // Sizes can optionally end in `b`, like `8kb`
+        let size = size.strip_suffix('b').unwrap_or(&size);
+
+        let (size, scale) = match size.as_bytes().last() {
+            Some(b'k') => (&size[..size.len() - 1], 1024),
+            Some(b'm') => (&size[..size.len() - 1], 1024 * 1024),
+            Some(b'g') => (&size[..size.len() - 1], 1024 * 1024 * 1024),
+            _ => (size, 1),
+        };
+
+        let max = size
+            .trim()
+            .parse::<usize>()
+            .ok()?
+            .checked_mul(scale)
+            .filter(|max| *max > 0)?;
+
+        Some((addr, Some(max)))
+    }
+}
+
+impl FromStr for Bind {
+    type Err = ParseBindError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Addresses often come from environment variables,
+        // so surrounding whitespace is ignored
+        let s = s.trim();
+
+        let (scheme, addr) = match s.find(Self::SCHEME_SEPARATOR) {
+            Some(end) => (&s[..end + Self::SCHEME_SEPARATOR.len()], &s[end + Self::SCHEME_SEPARATOR.len()..]),
+            // Addresses without a scheme are UDP
+            None => (Self::UDP_SCHEME, s),
+        };
+
+        let err = || ParseBindError(s.to_owned());
+
+        match &*scheme.to_ascii_lowercase() {
+            Self::UDP_SCHEME => {
+                let (addr, max) = Self::parse_max(addr).ok_or_else(err)?;
+
+                Ok(Bind::Udp(addr.to_owned(), max))
+            }
+            Self::HTTP_SCHEME => {
+                let (addr, max) = Self::parse_max(addr).ok_or_else(err)?;
+
+                Ok(Bind::Http(addr.trim_end_matches('/').to_owned(), max))
+            }
+            Self::UNIX_SCHEME => Ok(Bind::Unix(addr.into())),
+            _ => Err(err()),
+        }
+    }
+}
+
+/**
+An address to bind with a scheme that isn't supported.
+*/
+#[derive(Debug)]
+pub struct ParseBindError(String);
+
+impl fmt::Display for ParseBindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` isn't a supported address; expected a scheme of `udp://`, `http://`, or `unix://`, and an optional size like `?max=8k`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseBindError {}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bind::Udp(addr, _) => write!(f, "{}{}", Self::UDP_SCHEME, addr)?,
+            Bind::Http(addr, _) => write!(f, "{}{}", Self::HTTP_SCHEME, addr)?,
+            Bind::Unix(path) => write!(f, "{}{}", Self::UNIX_SCHEME, path.display())?,
+        }
+
+        match self {
+            Bind::Udp(_, Some(max)) | Bind::Http(_, Some(max)) => write!(f, "?{}{}", Self::MAX_PARAM, max),
+            _ => Ok(()),
+        }
+    }
+}
+
+/**
+Build a server to receive GELF messages and process them.
+
+A socket is bound for each address in `config.bind`. If an address
+fails to bind then the error is reported and the remaining addresses
+are still bound. Building fails if no addresses could be bound.
+
+The addresses that were actually bound are available from the returned
+server, which is useful when binding to port `0`.
+*/
+pub fn build(
+    config: Config,
+    receive: impl FnMut(Bytes) -> Result<Option<Message>, Error> + Send + Sync + 'static,
+    handle: impl FnMut(Message) -> Result<(), Error> + Send + Sync + 'static,
+) -> Result<Server<impl Future<Item = (), Error = Exit>>, Error> {
+    build_with_transform(config, receive, |_| Ok(true), handle)
+}
+
+/**
+Build a server to receive GELF messages, transform them, and process them.
+
+The `transform` runs on each received message before it's handled. If it
+returns `false` then the message is dropped. If it returns an error then
+the message fails to be processed.
+*/
+pub fn build_with_transform(
+    config: Config,
+    mut receive: impl FnMut(Bytes) -> Result<Option<Message>, Error> + Send + Sync + 'static,
+    mut transform: impl FnMut(&mut Message) -> Result<bool, Error> + Send + Sync + 'static,
+    mut handle: impl FnMut(Message) -> Result<(), Error> + Send + Sync + 'static,
+) -> Result<Server<impl Future<Item = (), Error = Exit>>, Error> {
+    if config.selftest {
+        match selftest(&mut receive, &mut transform, &mut handle) {
+            Ok(()) => emit("GELF server self-test passed"),
+            Err(err) if config.selftest_fatal => {
+                bail!("GELF server self-test failed: {}", err)
+            }
+            Err(err) => emit_warn(
+                "GELF server self-test failed: {Error}",
+                json!({ "Error": err.to_string() }),
+            ),
+        }
+    }
+
+    let listeners: Vec<_> = config
+        .bind
+        .0
+        .iter()
+        .filter_map(|bind| {
+            Listener::bind(bind, &config)
+                .map_err(|e| emit_err(&e, "GELF server bind failed"))
+                .ok()
+        })
+        .collect();
+
+    if listeners.is_empty() {
+        bail!("no addresses could be bound from {:?}", config.bind.0);
+    }
+
+    serve(config, listeners, receive, transform, handle)
+}
+
+/**
+Build a server that receives GELF messages from already bound listeners.
+*/
+fn serve(
+    config: Config,
+    listeners: Vec<Listener>,
+    receive: impl FnMut(Bytes) -> Result<Option<Message>, Error> + Send + Sync + 'static,
+    mut transform: impl FnMut(&mut Message) -> Result<bool, Error> + Send + Sync + 'static,
+    mut handle: impl FnMut(Message) -> Result<(), Error> + Send + Sync + 'static,
+) -> Result<Server<impl Future<Item = (), Error = Exit>>, Error> {
+    let local_addrs = listeners.iter().filter_map(Listener::local_addr).collect();
+
+    #[cfg(target_os = "linux")]
+    let udp_drops = {
+        use std::os::unix::io::AsRawFd;
+
+        let fds: Vec<_> = listeners
+            .iter()
+            .filter_map(|listener| match listener {
+                Listener::Udp(sock, ..) => Some(sock.as_raw_fd()),
+                _ => None,
+            })
+            .collect();
+
+        crate::udp_drops::UdpDrops::new(&fds)
+    };
+
+    // Bind an HTTP server for each distinct diagnostics address
+    // Each server responds to both metrics and health checks
+    let http_binds: BTreeSet<_> = config
+        .metrics_bind
+        .iter()
+        .chain(config.health_bind.iter())
+        .collect();
+
+    let http = http_binds
+        .into_iter()
+        .map(|addr| {
+            http::bind(addr)
+                .map_err(|e| err_msg(format_args!("failed to bind HTTP to {}: {}", addr, e)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    HEALTH.ready();
+
+    // Chunked messages are reassembled by the receiver,
+    // so it needs to be shared by all sockets
+    let receive = Arc::new(Mutex::new(receive));
+
+    let (audit, audit_writer) = match config.audit_path {
+        Some(ref path) => {
+            let (audit, writer) = Audit::spawn(path, config.audit_capacity)?;
+
+            (Some(audit), Some(writer))
+        }
+        None => (None, None),
+    };
+
+    let (tx, rx) = queue::channel(config.unprocessed_capacity, config.queue_overflow_policy);
+    let unprocessed = tx.len();
+
+    let mut sampler = Sampler::new(config.sample_rate, config.sample_seed);
+
+    let (drain_tx, drain_rx) = oneshot::channel();
+    let handle_state = Arc::new(HandleState {
+        drain: Mutex::new(Some(drain_tx)),
+        draining: AtomicBool::new(false),
+        idle: AtomicBool::new(false),
+    });
+    let state = handle_state.clone();
+
+    // Attempt to bind shutdown signals
+    // On Unix, `SIGTERM` is also a shutdown signal, because it's what
+    // container orchestrators send to stop a process
+    #[cfg(unix)]
+    let shutdown = {
+        use tokio_signal::unix::{Signal, SIGTERM};
+
+        tokio_signal::ctrl_c()
+            .join(Signal::new(SIGTERM))
+            .map(|(ctrl_c, sigterm)| {
+                Box::new(
+                    ctrl_c
+                        .map(|_| ShutdownReason::CtrlC)
+                        .select(sigterm.map(|_| ShutdownReason::Sigterm)),
+                ) as tokio_signal::IoStream<ShutdownReason>
+            })
+    };
+
+    #[cfg(not(unix))]
+    let shutdown = tokio_signal::ctrl_c().map(|ctrl_c| {
+        Box::new(ctrl_c.map(|_| ShutdownReason::CtrlC)) as tokio_signal::IoStream<ShutdownReason>
+    });
+
+    let shutdown = shutdown.map_err(emit_abort_with("Server setup failed", exit_failure));
+
+    let run = shutdown.and_then(move |shutdown| {
+        // Spawn a background thread to process GELF payloads
+        // This runs on its own thread so a slow consumer of processed
+        // messages doesn't stop sockets from being read
+        // The thread completes once every buffered message has been processed
+        // after the server stops receiving new ones
+        let (processed_tx, processed_rx) = oneshot::channel();
+        let processed_state = state.clone();
+        thread::spawn(move || {
+            let process = rx
+                .for_each(move |mut msg| {
+                    let handled = transform(&mut msg).and_then(|keep| {
+                        if keep {
+                            handle(msg).map(|()| true)
+                        } else {
+                            Ok(false)
+                        }
+                    });
+
+                    match handled {
+                        Ok(true) => increment!(process_ok),
+                        Ok(false) => increment!(transform_dropped),
+                        Err(err) => {
+                            increment!(process_err);
+                            increment_kind(&err);
+                            emit_err(&err, "GELF processing failed");
+                        }
+                    }
+
+                    Ok(())
+                })
+                .then(move |_| {
+                    processed_state.idle.store(true, Ordering::Release);
+
+                    processed_tx.send(())
+                })
+                .map_err(|_| ());
+
+            let _ = tokio::runtime::current_thread::block_on_all(process);
+        });
+
+        // Spawn a background thread to serve diagnostics over HTTP
+        // This runs on its own thread so it keeps responding
+        // even if processing messages is saturated
+        // The thread completes once the server shuts down
+        let diagnostics = if !http.is_empty() {
+            let (stop_tx, stop_rx) = oneshot::channel::<()>();
+
+            let serve = thread::spawn(move || {
+                let _ = tokio::runtime::current_thread::block_on_all(
+                    future::join_all(http).select2(stop_rx),
+                );
+            });
+
+            Some((stop_tx, serve))
+        } else {
+            None
+        };
+
+        // Spawn a background task to periodically sample metrics for rates
+        if config.metrics_rate_window_ms > 0 {
+            let window = Duration::from_millis(config.metrics_rate_window_ms);
+            let interval = cmp::min(window, Duration::from_secs(1));
+
+            tokio::spawn(
+                Interval::new(Instant::now(), interval)
+                    .for_each(move |_| {
+                        if let Ok(mut rates) = RATES.lock() {
+                            rates.sample(window);
+                        }
+
+                        Ok(())
+                    })
+                    .map_err(emit_abort("GELF metrics collection failed")),
+            );
+        }
+
+        // Spawn a background task to periodically emit metrics
+        if config.metrics_interval_ms > 0 {
+            let interval = Duration::from_millis(config.metrics_interval_ms);
+
+            tokio::spawn(
+                Interval::new(Instant::now() + interval, interval)
+                    .for_each(|_| {
+                        emit_metrics();
+
+                        Ok(())
+                    })
+                    .map_err(emit_abort("GELF metrics collection failed")),
+            );
+        }
+
+        // Spawn a background task to periodically sample datagrams dropped by the kernel
+        #[cfg(target_os = "linux")]
+        {
+            let mut udp_drops = udp_drops;
+
+            if !udp_drops.is_empty() {
+                tokio::spawn(
+                    Interval::new(Instant::now(), Duration::from_secs(1))
+                        .for_each(move |_| {
+                            METRICS.udp_kernel_drops.add(udp_drops.sample() as usize);
+
+                            Ok(())
+                        })
+                        .map_err(emit_abort("GELF metrics collection failed")),
+                );
+            }
+        }
+
+        // Spawn a background task to dump metrics to `stderr` on `SIGUSR1`
+        // This is a no-op on platforms without it
+        #[cfg(unix)]
+        {
+            use tokio_signal::unix::{Signal, SIGUSR1};
+
+            tokio::spawn(
+                Signal::new(SIGUSR1)
+                    .flatten_stream()
+                    .for_each(|_| {
+                        dump_metrics();
+
+                        Ok(())
+                    })
+                    .map_err(emit_abort("GELF metrics dump failed")),
+            );
+        }
+
+        // Spawn a background task to poll `stdio`
+        let stdin_closed = if config.wait_on_stdin {
+            Either::A(stdin_closed()
+                .map(|_| Op::Shutdown(ShutdownReason::StdinClosed)))
+        } else {
+            Either::B(future::empty())
+        }.into_stream();
+
+        // Listen for a drain requested through a `Handle`
+        // If every handle is dropped then the server keeps running
+        let drained = drain_rx
+            .then(|r| match r {
+                Ok(()) => Either::A(future::ok(Op::Shutdown(ShutdownReason::HandleClose))),
+                Err(_) => Either::B(future::empty()),
+            })
+            .into_stream();
+
+        // Listen for Ctrl + C and other termination signals
+        // from the OS
+        let shutdown = shutdown
+            .map(Op::Shutdown)
+            .map_err(emit_abort("Server shutdown was unclean"));
+
+        // Any Unix domain sockets are removed when the server shuts down
+        let mut unlink = Vec::new();
+
+        // Accept and process incoming GELF messages
+        // This stream should never return an `Err` variant
+        let server = listeners
+            .into_iter()
+            .map(|listener| listener.into_stream(Decode(receive.clone(), audit.clone()), &mut unlink))
+            .fold(
+                Box::new(stream::empty()) as Received,
+                |merged, server| Box::new(merged.select(server)),
+            );
+
+        server
+            .select(shutdown)
+            .select(stdin_closed)
+            .select(drained)
+            // Anything that stops the stream without a shutdown being
+            // requested is treated as a listener failing
+            .map_err(|()| ShutdownReason::ListenerError)
+            .and_then(|msg| match msg {
+                // Continue processing received messages
+                Op::Receive(msg) => Ok(msg),
+                // Terminate on shutdown messages
+                // The error here causes the future to return
+                Op::Shutdown(reason) => Err(reason),
+            })
+            // Process messages
+            .filter_map(|msg| msg)
+            // Drop messages that aren't sampled
+            .filter(move |_| {
+                let keep = sampler.keep();
+
+                if !keep {
+                    increment!(sampled_out);
+                }
+
+                keep
+            })
+            // What happens when the processing task can't keep up
+            // depends on the queue's overflow policy
+            .forward(tx.sink_map_err(|()| {
+                // The processing thread has stopped, so messages can't be processed
+                emit_err(&err_msg("the GELF processing queue is closed"), "GELF buffering failed");
+
+                ShutdownReason::ListenerError
+            }))
+            // If we get this far then the server is shutting down
+            // Wait for the message pipeline to drain, giving up
+            // if it takes longer than the grace period
+            .then(move |r| {
+                let reason = r.err().unwrap_or(ShutdownReason::ListenerError);
+
+                state.draining.store(true, Ordering::Release);
+                HEALTH.unavailable();
+                // This is logged at a higher level than other lifecycle events
+                // so the reason a server stopped is kept in aggregated logs
+                emit_info("Shutting down because of {Reason}", json!({ "Reason": reason }));
+
+                // The listeners have been dropped at this point, so the audit
+                // log's writer completes once it's written any queued payloads
+                let audited = match audit_writer {
+                    Some(writer) => {
+                        let (audited_tx, audited_rx) = oneshot::channel();
+
+                        thread::spawn(move || {
+                            let _ = writer.join();
+                            let _ = audited_tx.send(());
+                        });
+
+                        Either::A(audited_rx)
+                    }
+                    None => Either::B(future::ok(())),
+                };
+
+                processed_rx
+                    .join(audited)
+                    .map(|((), ())| ())
+                    .timeout(Duration::from_millis(config.shutdown_grace_ms))
+            })
+            // FIXME: Forces the runtime to shutdown
+            // This is a bit of a hack that prevents
+            // `tokio` from waiting on any remaining futures
+            // since we're terminating the process
+            .then(move |r| {
+                HEALTH.unavailable();
+                drop(unlink);
+
+                // Stop serving diagnostics so their addresses are unbound
+                if let Some((stop, serve)) = diagnostics {
+                    let _ = stop.send(());
+                    let _ = serve.join();
+                }
+
+                match r {
+                    Ok(()) => Err(Exit::Clean),
+                    Err(ref e) if e.is_elapsed() => {
+                        emit_warn(
+                            "GELF processing did not complete within the shutdown grace period; {Unprocessed} messages were abandoned",
+                            json!({
+                                "Unprocessed": unprocessed.get(),
+                                "Reason": ShutdownReason::DrainTimeout,
+                            }),
+                        );
+
+                        Err(Exit::Abandoned)
+                    }
+                    Err(_) => Err(Exit::Failure),
+                }
+            })
+    });
+
+    Ok(Server {
+        local_addrs,
+        handle: Handle(handle_state),
+        run,
+    })
+}
+
+/**
+A built server.
+
+The server runs when it's polled as a future.
+*/
+pub struct Server<F> {
+    local_addrs: Vec<SocketAddr>,
+    handle: Handle,
+    run: F,
+}
+
+impl<F> Server<F> {
+    /**
+    Get a handle to control the server while it's running.
+    */
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /**
+    The socket addresses the server is bound to.
+
+    Unix domain sockets aren't included.
+    */
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+}
+
+impl<F> Future for Server<F>
+where
+    F: Future<Item = (), Error = Exit>,
+{
+    type Item = ();
+    type Error = Exit;
+
+    fn poll(&mut self) -> Poll<(), Exit> {
+        self.run.poll()
+    }
+}
+
+/**
+A handle to control a running server.
+
+Handles can be sent to other threads.
+*/
+#[derive(Clone)]
+pub struct Handle(Arc<HandleState>);
+
+struct HandleState {
+    drain: Mutex<Option<oneshot::Sender<()>>>,
+    draining: AtomicBool,
+    idle: AtomicBool,
+}
+
+impl Handle {
+    /**
+    Stop receiving new messages and finish processing the ones already received.
+
+    This is the same as the server receiving a termination signal.
+    Calling `drain` more than once has no further effect.
+    */
+    pub fn drain(&self) {
+        let drain = self.0.drain.lock().ok().and_then(|mut drain| drain.take());
+
+        if let Some(drain) = drain {
+            let _ = drain.send(());
+        }
+    }
+
+    /**
+    Whether the server has stopped receiving new messages.
+    */
+    pub fn is_draining(&self) -> bool {
+        self.0.draining.load(Ordering::Acquire)
+    }
+
+    /**
+    Whether the server has stopped receiving new messages and
+    finished processing all of the ones it already received.
+    */
+    pub fn is_idle(&self) -> bool {
+        self.0.idle.load(Ordering::Acquire)
+    }
+}
+
+/**
+The outcome of shutting down the server.
+*/
+pub enum Exit {
+    /**
+    The server was terminated, but was done so cleanly.
+    */
+    Clean,
+    /**
+    The server was terminated, but due to an internal error.
+    */
+    Failure,
+    /**
+    The server was terminated, but some unprocessed messages were abandoned
+    because they weren't processed within the shutdown grace period.
+    */
+    Abandoned,
+}
+
+fn exit_failure() -> Exit {
+    Exit::Failure
+}
+
+type Received = Box<dyn Stream<Item = Op, Error = ()> + Send>;
+
+/**
+The maximum number of HTTP connections to read GELF messages from at once.
+
+Any more connections will wait to be accepted.
+*/
+const MAX_HTTP_CONNECTIONS: usize = 64;
+
+enum Listener {
+    Udp(UdpSocket, UdpOptions),
+    Http(TcpListener, usize),
+    #[cfg(unix)]
+    Unix(UnixDatagram, Unlink),
+    /**
+    Payloads sent through a channel instead of a socket.
+
+    This lets tests drive the server without any networking.
+    A payload can also be an error, which is received as if
+    the socket had failed.
+    */
+    #[cfg(test)]
+    Memory(mpsc::UnboundedReceiver<io::Result<Bytes>>),
+}
+
+impl Listener {
+    fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Udp(socket, ..) => socket.local_addr().ok(),
+            Listener::Http(listener, _) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(..) => None,
+            #[cfg(test)]
+            Listener::Memory(..) => None,
+        }
+    }
+
+    fn bind(bind: &Bind, config: &Config) -> Result<Self, Error> {
+        let listener = match bind {
+            Bind::Udp(addr, max) => Self::bind_udp(addr, max.unwrap_or(config.udp_max_size_bytes), config),
+            Bind::Http(addr, max) => Self::bind_http(addr, max.unwrap_or(config.http_max_body_bytes)),
+            Bind::Unix(path) => Self::bind_unix(path),
+        };
+
+        listener.map_err(|e| err_msg(format_args!("failed to bind {}: {}", bind, e)))
+    }
+
+    fn bind_udp(addr: &str, max_size_bytes: usize, config: &Config) -> Result<Self, Error> {
+        let addr = resolve_addr(addr)?;
+
+        let sock = Self::bind_udp_socket(addr, config)?;
+        let sock = UdpSocket::from_std(sock, &reactor::Handle::default())?;
+
+        let limit = config.udp_rate_limit_per_sec.map(|per_sec| {
+            RateLimit::new(rate_limit::Config {
+                per_sec,
+                burst: config.udp_rate_limit_burst.unwrap_or(per_sec),
+                capacity: config.udp_rate_limit_sources,
+            })
+        });
+
+        Ok(Listener::Udp(
+            sock,
+            UdpOptions {
+                limit,
+                max_size_bytes,
+                split_on_null: config.udp_split_on_null,
+            },
+        ))
+    }
+
+    fn bind_http(addr: &str, max_body_bytes: usize) -> Result<Self, Error> {
+        let addr = resolve_addr(addr)?;
+
+        let listener = TcpListener::bind(&addr)?;
+
+        Ok(Listener::Http(listener, max_body_bytes))
+    }
+
+    fn bind_udp_socket(addr: SocketAddr, config: &Config) -> Result<std::net::UdpSocket, Error> {
+        let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+        if addr.is_ipv6() {
+            // A socket that can't receive IPv4 datagrams isn't fatal
+            // IPv4 clients just won't be able to reach it
+            if let Err(e) = sock.set_only_v6(!config.dual_stack) {
+                emit_warn(
+                    "IPV6_V6ONLY couldn't be set for {Addr}",
+                    json!({
+                        "Addr": addr,
+                        "DualStack": config.dual_stack,
+                        "Error": e.to_string(),
+                    }),
+                );
+            }
+        }
+
+        if let Some(size) = config.udp_recv_buffer_bytes {
+            // A receive buffer that can't be resized isn't fatal
+            // The socket will just be more likely to drop datagrams
+            let set = sock.set_recv_buffer_size(size);
+            let actual = sock.recv_buffer_size()?;
+
+            match set {
+                Ok(()) if actual >= size => (),
+                Ok(()) => emit_warn(
+                    "The UDP receive buffer for {Addr} was clamped to {ActualBytes} bytes",
+                    json!({
+                        "Addr": addr,
+                        "RequestedBytes": size,
+                        "ActualBytes": actual,
+                    }),
+                ),
+                Err(e) => emit_warn(
+                    "The UDP receive buffer for {Addr} couldn't be set, so it is {ActualBytes} bytes",
+                    json!({
+                        "Addr": addr,
+                        "RequestedBytes": size,
+                        "ActualBytes": actual,
+                        "Error": e.to_string(),
+                    }),
+                ),
+            }
+        }
+
+        if config.udp_reuse_port {
+            // A socket that can't be shared isn't fatal
+            // Other processes just won't be able to bind the same address
+            if let Err(e) = Self::set_reuse_port(&sock) {
+                emit_warn(
+                    "SO_REUSEPORT couldn't be set for {Addr}, so it can't be shared",
+                    json!({
+                        "Addr": addr,
+                        "Error": e.to_string(),
+                    }),
+                );
+            }
+        }
+
+        sock.bind(&addr.into())?;
+        sock.set_nonblocking(true)?;
+
+        Ok(sock.into())
+    }
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    fn set_reuse_port(sock: &Socket) -> io::Result<()> {
+        sock.set_reuse_address(true)?;
+        sock.set_reuse_port(true)
+    }
+
+    #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+    fn set_reuse_port(_: &Socket) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "SO_REUSEPORT isn't supported on this platform",
+        ))
+    }
+
+    #[cfg(unix)]
+    fn bind_unix(path: &Path) -> Result<Self, Error> {
+        use std::{fs, os::unix::fs::FileTypeExt};
+
+        // Remove a stale socket left behind by a previous run
+        // Anything at the path that isn't a socket is left alone
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                bail!("{} already exists and is not a socket", path.display());
+            }
+
+            fs::remove_file(path)?;
+        }
+
+        let sock = UnixDatagram::bind(path)?;
+
+        Ok(Listener::Unix(sock, Unlink(path.to_owned())))
+    }
+
+    #[cfg(not(unix))]
+    fn bind_unix(_: &Path) -> Result<Self, Error> {
+        bail!("unix domain sockets are not supported on this platform")
+    }
+
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    fn into_stream<F>(self, decode: Decode<F>, unlink: &mut Vec<Unlink>) -> Received
+    where
+        F: FnMut(Bytes) -> Result<Option<Message>, Error> + Send + Sync + 'static,
+    {
+        match self {
+            Listener::Udp(sock, mut udp) if !udp.split_on_null => Box::new(
+                Datagrams::new(sock)
+                    .then(move |datagram| {
+                        let (src, addr) = datagram?;
+
+                        decode.audit(&src, Some(addr));
+
+                        if !udp.admit(&src, addr) {
+                            return Ok(None);
+                        }
+
+                        decode.receive(src, Some(addr))
+                    })
+                    .map(Op::Receive)
+                    .or_else(receive_failed),
+            ),
+            Listener::Udp(sock, mut udp) => Box::new(
+                Datagrams::new(sock)
+                    .then(move |datagram| {
+                        let (src, addr) = datagram?;
+
+                        decode.audit(&src, Some(addr));
+
+                        if !udp.admit(&src, addr) {
+                            return Ok(Vec::new());
+                        }
+
+                        // Each frame is received independently, so one
+                        // invalid frame doesn't drop the rest of the datagram
+                        let ops = split_on_null(src)
+                            .map(|frame| {
+                                decode
+                                    .receive(frame, Some(addr))
+                                    .map(Op::Receive)
+                                    .or_else(receive_failed)
+                                    .unwrap_or(Op::Receive(None))
+                            })
+                            .collect();
+
+                        Ok(ops)
+                    })
+                    .or_else(|err| receive_failed(err).map(|op| vec![op]))
+                    .map(stream::iter_ok)
+                    .flatten(),
+            ),
+            Listener::Http(listener, max_body_bytes) => Box::new(
+                listener
+                    .incoming()
+                    // Failing to accept a single connection shouldn't stop the server
+                    .then(|conn| {
+                        Ok::<_, ()>(conn.map_err(|e| emit_err(&e, "HTTP accept failed")).ok())
+                    })
+                    .filter_map(|conn| conn)
+                    .map(move |conn| {
+                        let decode = decode.clone();
+
+                        let peer = conn.peer_addr().ok();
+
+                        emit_trace("Accepted an HTTP connection from {Peer}", || {
+                            json!({ "Peer": peer })
+                        });
+
+                        http::ingest(conn, max_body_bytes, move |body| {
+                            decode.audit(&body, peer);
+
+                            decode
+                                .receive(body, peer)
+                                .map(|msg| {
+                                    msg.map(|mut msg| {
+                                        msg.set_peer(peer);
+                                        msg
+                                    })
+                                })
+                                .map_err(|err| {
+                                    let _ = receive_failed(err);
+                                })
+                        })
+                    })
+                    .buffer_unordered(MAX_HTTP_CONNECTIONS)
+                    .map(Op::Receive),
+            ),
+            #[cfg(unix)]
+            Listener::Unix(sock, path) => {
+                unlink.push(path);
+
+                Box::new(
+                    UnixDatagramFramed::<PathBuf, _>::new(sock, decode)
+                        .map(|(msg, _)| Op::Receive(msg))
+                        .or_else(receive_failed),
+                )
+            }
+            #[cfg(test)]
+            Listener::Memory(rx) => Box::new(
+                rx.then(move |src| match src {
+                    Ok(Ok(src)) => {
+                        decode.audit(&src, None);
+                        decode.receive(src, None)
+                    }
+                    Ok(Err(err)) => Err(ReceiveError::Socket(err)),
+                    Err(()) => Ok(None),
+                })
+                .map(Op::Receive)
+                .or_else(receive_failed),
+            ),
+        }
+    }
+}
+
+/**
+Get the socket address to bind for an address like `0.0.0.0:12201` or `localhost:12201`.
+
+Addresses that aren't literal socket addresses are resolved as hostnames,
+and the first resolved address is used. This only happens while the
+server is being built, so it's fine for resolution to block.
+*/
+pub(crate) fn resolve_addr(addr: &str) -> Result<SocketAddr, Error> {
+    if let Ok(addr) = addr.parse() {
+        return Ok(addr);
+    }
+
+    addr.to_socket_addrs()
+        .map_err(|e| err_msg(format_args!("failed to resolve `{}`: {}", addr, e)))?
+        .next()
+        .ok_or_else(|| err_msg(format_args!("failed to resolve `{}`: no addresses were found", addr)))
+}
+
+/**
+Options for receiving datagrams on a UDP socket.
+*/
+struct UdpOptions {
+    limit: Option<RateLimit>,
+    max_size_bytes: usize,
+    split_on_null: bool,
+}
+
+impl UdpOptions {
+    /**
+    Check whether a datagram should be decoded.
+
+    Datagrams are checked before they're decoded so oversized datagrams,
+    and floods from a single source, are cheap to drop.
+    */
+    fn admit(&mut self, src: &Bytes, addr: SocketAddr) -> bool {
+        if src.len() > self.max_size_bytes {
+            increment!(udp_msg_overflow);
+            emit_debug(
+                "Dropped a {Size} byte datagram from {Source} that exceeded the maximum size",
+                json!({ "Size": src.len(), "Source": addr }),
+            );
+
+            return false;
+        }
+
+        if let Some(ref mut limit) = self.limit {
+            if !limit.allow(addr.ip()) {
+                increment!(rate_limited);
+
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/**
+A stream of datagrams received on a UDP socket, along with their source address.
+*/
+struct Datagrams {
+    sock: UdpSocket,
+    buf: Vec<u8>,
+}
+
+impl Datagrams {
+    /**
+    The largest possible UDP payload.
+    */
+    const MAX_DATAGRAM_BYTES: usize = 64 * 1024;
+
+    fn new(sock: UdpSocket) -> Self {
+        Datagrams {
+            sock,
+            buf: vec![0; Self::MAX_DATAGRAM_BYTES],
+        }
+    }
+}
+
+impl Stream for Datagrams {
+    type Item = (Bytes, SocketAddr);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let (read, addr) = futures::try_ready!(self.sock.poll_recv_from(&mut self.buf));
+
+        Ok(Async::Ready(Some((Bytes::from(&self.buf[..read]), addr))))
+    }
+}
+
+/**
+Split a datagram into frames on null bytes.
+
+Datagrams that don't look like uncompressed JSON are returned whole,
+because chunked and compressed datagrams can contain null bytes.
+*/
+fn split_on_null(src: Bytes) -> impl Iterator<Item = Bytes> {
+    let is_json = match src.iter().find(|b| !b.is_ascii_whitespace()) {
+        // A JSON object, or a UTF-8 byte order mark
+        Some(b'{') | Some(0xef) => true,
+        _ => false,
+    };
+
+    let mut frames = Vec::new();
+
+    if is_json {
+        let mut start = 0;
+        for end in src.iter().enumerate().filter(|(_, b)| **b == 0).map(|(i, _)| i) {
+            frames.push(src.slice(start, end));
+            start = end + 1;
+        }
+
+        frames.push(src.slice_from(start));
+    } else {
+        frames.push(src);
+    }
+
+    // Empty frames, like the one after a trailing null, are skipped
+    frames.into_iter().filter(|frame| !frame.is_empty())
+}
+
+/**
+Removes a Unix domain socket from the filesystem when dropped.
+*/
+struct Unlink(PathBuf);
+
+impl Drop for Unlink {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+struct Decode<F>(Arc<Mutex<F>>, Option<Audit>);
+
+impl<F> Clone for Decode<F> {
+    fn clone(&self) -> Self {
+        Decode(self.0.clone(), self.1.clone())
+    }
+}
+
+impl<F> Decode<F>
+where
+    F: FnMut(Bytes) -> Result<Option<Message>, Error>,
+{
+    /**
+    Record a raw payload in the audit log, if there is one.
+    */
+    fn audit(&self, src: &Bytes, peer: Option<SocketAddr>) {
+        if let Some(ref audit) = self.1 {
+            audit.record(src, peer);
+        }
+    }
+
+    /**
+    Receive a raw payload from `peer`.
+
+    The peer is attached to any message that's received from the payload.
+    */
+    fn receive(&self, src: Bytes, peer: Option<SocketAddr>) -> Result<Option<Message>, ReceiveError> {
+        // Empty payloads can't be valid GELF, so they're skipped
+        // instead of being counted as received
+        if src.is_empty() {
+            increment!(receive_empty);
+
+            return Ok(None);
+        }
+
+        MESSAGE_SIZE_BYTES.observe(src.len());
+
+        let mut receive = self
+            .0
+            .lock()
+            .map_err(|_| ReceiveError::Decode(err_msg("GELF receiver is poisoned")))?;
+
+        match (receive)(src) {
+            Ok(mut msg) => {
+                increment!(receive_ok);
+
+                if let Some(ref mut msg) = msg {
+                    msg.set_source(peer);
+                }
+
+                Ok(msg)
+            }
+            Err(err) => {
+                increment!(receive_err);
+                increment_kind(&err);
+
+                Err(ReceiveError::Decode(err))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl<F> Decoder for Decode<F>
+where
+    F: FnMut(Bytes) -> Result<Option<Message>, Error>,
+{
+    // The item is itself optional, because a datagram may
+    // only be part of a message. Framed UDP streams end when
+    // the decoder doesn't produce an item.
+    type Item = Option<Message>;
+    type Error = ReceiveError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let src = src.take().freeze();
+
+        // Unix domain sockets don't have a peer address
+        self.audit(&src, None);
+
+        self.receive(src, None).map(Some)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Op {
+    Receive(Option<Message>),
+    Shutdown(ShutdownReason),
+}
+
+/**
+Why the server is shutting down.
+
+This is included in diagnostics as the `Reason` property.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ShutdownReason {
+    /**
+    A drain was requested through a `Handle`.
+    */
+    HandleClose,
+    /**
+    Ctrl + C was pressed.
+    */
+    CtrlC,
+    /**
+    The process was sent `SIGTERM`.
+    */
+    Sigterm,
+    /**
+    `stdin` was closed while `wait_on_stdin` was set.
+    */
+    StdinClosed,
+    /**
+    The server stopped receiving messages without a shutdown being requested.
+    */
+    ListenerError,
+    /**
+    Messages weren't processed within the shutdown grace period.
+    */
+    DrainTimeout,
+}
+
+/**
+An error receiving a datagram.
+*/
+enum ReceiveError {
+    /**
+    The datagram couldn't be read from the socket.
+    */
+    Socket(io::Error),
+    /**
+    The datagram couldn't be decoded.
+    */
+    Decode(Error),
+}
+
+impl From<io::Error> for ReceiveError {
+    fn from(err: io::Error) -> Self {
+        ReceiveError::Socket(err)
+    }
+}
+
+/**
+Send a synthetic GELF message through the receiver and processor.
+*/
+fn selftest(
+    receive: &mut impl FnMut(Bytes) -> Result<Option<Message>, Error>,
+    transform: &mut impl FnMut(&mut Message) -> Result<bool, Error>,
+    handle: &mut impl FnMut(Message) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let payload = json!({
+        "version": "1.1",
+        "host": "sqelf",
+        "short_message": "GELF server self-test",
+        "_selftest": true,
+    });
+
+    let mut msg = receive(Bytes::from(payload.to_string()))?
+        .ok_or_else(|| err_msg("the self-test message wasn't received"))?;
+
+    if !transform(&mut msg)? {
+        bail!("the self-test message was dropped by a transform");
+    }
+
+    handle(msg)
+}
+
+fn receive_failed(err: ReceiveError) -> Result<Op, ()> {
+    match err {
+        ReceiveError::Socket(err) => {
+            increment!(socket_err);
+            emit_err(&err, "GELF socket receive failed");
+        }
+        ReceiveError::Decode(err) => emit_err(&err, "GELF receive failed"),
+    }
+
+    Ok(Op::Receive(None))
+}
+
+/**
+Increment the metric counter for the kind of an error.
+
+Errors without a specific kind aren't counted.
+*/
+pub(crate) fn increment_kind(err: &Error) {
+    match err.kind() {
+        ErrorKind::Reassembly => increment!(reassembly_err),
+        ErrorKind::Decompress => increment!(decompress_err),
+        ErrorKind::Parse => increment!(parse_err),
+        ErrorKind::Other => (),
+    }
+}
+
+fn stdin_closed() -> impl Future<Item = (), Error = ()> {
+    let (tx, rx) = mpsc::channel(1);
 
     thread::spawn(move || 'wait: loop {
         match std::io::stdin().read(&mut [0]) {
@@ -196,3 +1882,574 @@ fn stdin_closed() -> impl Future<Item = (), Error = ()> {
 
     rx.into_future().map(|_| ()).map_err(|_| ())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_binds() {
+        let binds: Binds =
+            "0.0.0.0:12201, udp://[::]:12202,http://0.0.0.0:12203/,unix:///var/run/sqelf.sock,"
+                .parse()
+                .expect("failed to parse binds");
+
+        let expected = Binds(vec![
+            Bind::Udp("0.0.0.0:12201".to_owned(), None),
+            Bind::Udp("[::]:12202".to_owned(), None),
+            Bind::Http("0.0.0.0:12203".to_owned(), None),
+            Bind::Unix("/var/run/sqelf.sock".into()),
+        ]);
+
+        assert_eq!(expected, binds);
+    }
+
+    #[test]
+    fn parse_bind_max_sizes() {
+        let cases = vec![
+            ("udp://0.0.0.0:12201?max=8k", Some(Bind::Udp("0.0.0.0:12201".to_owned(), Some(8 * 1024)))),
+            ("0.0.0.0:12201?max=512", Some(Bind::Udp("0.0.0.0:12201".to_owned(), Some(512)))),
+            ("http://0.0.0.0:12202/?max=2MB", Some(Bind::Http("0.0.0.0:12202".to_owned(), Some(2 * 1024 * 1024)))),
+            ("udp://0.0.0.0:12201?max=0", None),
+            ("udp://0.0.0.0:12201?max=lots", None),
+            ("udp://0.0.0.0:12201?min=8k", None),
+        ];
+
+        for (bind, expected) in cases {
+            assert_eq!(expected, bind.parse().ok(), "unexpected parse of `{}`", bind);
+        }
+
+        let bind: Bind = "udp://0.0.0.0:12201?max=8k".parse().unwrap();
+        assert_eq!("udp://0.0.0.0:12201?max=8192", bind.to_string());
+        assert_eq!(bind, bind.to_string().parse().unwrap());
+    }
+
+    #[test]
+    fn bind_max_sizes_override_defaults() {
+        let config = Config::default();
+
+        let max_size_bytes = |bind: &str| match Listener::bind(&bind.parse().unwrap(), &config).unwrap() {
+            Listener::Udp(_, udp) => udp.max_size_bytes,
+            Listener::Http(_, max_body_bytes) => max_body_bytes,
+            _ => unreachable!(),
+        };
+
+        assert_eq!(config.udp_max_size_bytes, max_size_bytes("udp://127.0.0.1:0"));
+        assert_eq!(4, max_size_bytes("udp://127.0.0.1:0?max=4"));
+        assert_eq!(config.http_max_body_bytes, max_size_bytes("http://127.0.0.1:0"));
+        assert_eq!(1024, max_size_bytes("http://127.0.0.1:0?max=1k"));
+    }
+
+    #[test]
+    fn resolve_bind_addrs() {
+        assert_eq!("127.0.0.1:12201".parse::<SocketAddr>().unwrap(), resolve_addr("127.0.0.1:12201").unwrap());
+        assert_eq!("[::1]:12201".parse::<SocketAddr>().unwrap(), resolve_addr("[::1]:12201").unwrap());
+
+        assert!(resolve_addr("localhost:12201").unwrap().ip().is_loopback());
+
+        let err = resolve_addr("sqelf.invalid:12201").unwrap_err();
+        assert!(err.to_string().contains("sqelf.invalid"), "unexpected error `{}`", err);
+
+        assert!(resolve_addr("localhost").is_err());
+    }
+
+    #[test]
+    fn parse_bind_schemes() {
+        let cases = vec![
+            (" UDP://0.0.0.0:12201\n", Some(Bind::Udp("0.0.0.0:12201".to_owned(), None))),
+            ("Http://0.0.0.0:12202", Some(Bind::Http("0.0.0.0:12202".to_owned(), None))),
+            ("\tunix:///var/run/sqelf.sock ", Some(Bind::Unix("/var/run/sqelf.sock".into()))),
+            ("0.0.0.0:12203\r\n", Some(Bind::Udp("0.0.0.0:12203".to_owned(), None))),
+            ("tcp://0.0.0.0:12201", None),
+            ("https://0.0.0.0:12201", None),
+        ];
+
+        for (bind, expected) in cases {
+            assert_eq!(expected, bind.parse().ok(), "unexpected parse of `{}`", bind.escape_debug());
+        }
+
+        assert!("0.0.0.0:12201,tcp://0.0.0.0:12202".parse::<Binds>().is_err());
+    }
+
+    #[test]
+    fn config_builder() {
+        let default = serde_json::to_value(Config::default()).unwrap();
+        let built = serde_json::to_value(Config::builder().build()).unwrap();
+
+        assert_eq!(default, built);
+
+        let config = Config::builder()
+            .bind("http://127.0.0.1:12202".parse().unwrap())
+            .udp_rate_limit_per_sec(10)
+            .metrics_bind("127.0.0.1:9000")
+            .build();
+
+        assert_eq!(vec![Bind::Http("127.0.0.1:12202".to_owned(), None)], config.bind.0);
+        assert_eq!(Some(10), config.udp_rate_limit_per_sec);
+        assert_eq!(Some("127.0.0.1:9000"), config.metrics_bind.as_deref());
+        assert_eq!(Config::default().unprocessed_capacity, config.unprocessed_capacity);
+    }
+
+    /**
+    Build a server that receives payloads sent through the returned channel.
+    */
+    fn build_in_memory(
+        config: Config,
+        receive: impl FnMut(Bytes) -> Result<Option<Message>, Error> + Send + Sync + 'static,
+        handle: impl FnMut(Message) -> Result<(), Error> + Send + Sync + 'static,
+    ) -> (
+        mpsc::UnboundedSender<io::Result<Bytes>>,
+        Server<impl Future<Item = (), Error = Exit>>,
+    ) {
+        let (tx, rx) = mpsc::unbounded();
+
+        let server = serve(config, vec![Listener::Memory(rx)], receive, |_| Ok(true), handle)
+            .expect("failed to build server");
+
+        (tx, server)
+    }
+
+    #[test]
+    fn in_memory_receive_arms() {
+        let config = Config {
+            metrics_interval_ms: 0,
+            ..Default::default()
+        };
+
+        let (handled_tx, handled_rx) = std::sync::mpsc::channel();
+        let handled_tx = Mutex::new(handled_tx);
+
+        let mut gelf = receive_gelf();
+        let (tx, server) = build_in_memory(
+            config,
+            move |src: Bytes| match &*src {
+                b"incomplete" => Ok(None),
+                b"invalid" => Err(err_msg("invalid payload").with_kind(ErrorKind::Parse)),
+                _ => gelf(src),
+            },
+            move |msg| {
+                use crate::io::MemRead;
+
+                let msg = String::from_utf8_lossy(msg.bytes().unwrap()).into_owned();
+                let _ = handled_tx.lock().unwrap().send(msg);
+
+                Ok(())
+            },
+        );
+
+        let handle = server.handle();
+
+        let (exit_tx, exit_rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let exit = tokio::runtime::current_thread::block_on_all(server);
+            let _ = exit_tx.send(matches!(exit, Err(Exit::Clean)));
+        });
+
+        let (socket_err, receive_err, parse_err) = (
+            METRICS.socket_err.get(),
+            METRICS.receive_err.get(),
+            METRICS.parse_err.get(),
+        );
+
+        for src in [
+            Ok(Bytes::from_static(b"incomplete")),
+            Ok(Bytes::from_static(b"invalid")),
+            Err(io::Error::other("socket failed")),
+            Ok(Bytes::from_static(b"{\"short_message\":\"complete\"}")),
+        ] {
+            tx.unbounded_send(src).unwrap();
+        }
+
+        // Messages are received in order, so by the time the complete
+        // message is handled every other payload has been received
+        let handled = handled_rx.recv_timeout(Duration::from_secs(5)).expect("message wasn't handled");
+        assert_eq!("{\"short_message\":\"complete\"}", handled);
+
+        assert!(METRICS.socket_err.get() > socket_err);
+        assert!(METRICS.receive_err.get() > receive_err);
+        assert!(METRICS.parse_err.get() > parse_err);
+
+        handle.drain();
+
+        assert!(exit_rx.recv_timeout(Duration::from_secs(5)).expect("server didn't drain"));
+        assert!(handled_rx.try_recv().is_err());
+    }
+
+    fn receive_gelf() -> impl FnMut(Bytes) -> Result<Option<Message>, Error> + Send + Sync + 'static {
+        let mut receive = crate::receive::build(Default::default());
+        move |src| receive.decode(src)
+    }
+
+    #[test]
+    fn selftest_runs_before_binding() {
+        use crate::io::MemRead;
+
+        let config = || {
+            Config::builder()
+                .bind("127.0.0.1:0".parse().unwrap())
+                .selftest(true)
+                .build()
+        };
+
+        let handled = Arc::new(AtomicBool::new(false));
+        let server = {
+            let handled = handled.clone();
+
+            build(
+                config(),
+                receive_gelf(),
+                move |msg| {
+                    assert!(String::from_utf8_lossy(msg.bytes().unwrap()).contains("GELF server self-test"));
+                    handled.store(true, Ordering::SeqCst);
+
+                    Ok(())
+                },
+            )
+        };
+
+        assert!(server.is_ok());
+        assert!(handled.load(Ordering::SeqCst));
+
+        let err = build(
+            config(),
+            receive_gelf(),
+            |_| Err(err_msg("unreachable output")),
+        )
+        .err()
+        .expect("expected a failed self-test to fail the build");
+
+        assert!(err.to_string().contains("unreachable output"));
+
+        let server = build(
+            Config {
+                selftest_fatal: false,
+                ..config()
+            },
+            receive_gelf(),
+            |_| Err(err_msg("unreachable output")),
+        );
+
+        assert!(server.is_ok());
+    }
+
+    #[test]
+    fn receive_skips_empty_payloads() {
+        let decode = Decode(Arc::new(Mutex::new(|src: Bytes| {
+            assert!(!src.is_empty(), "empty payloads shouldn't be received");
+
+            Ok(None)
+        })), None);
+
+        let before = METRICS.receive_empty.get();
+
+        assert!(matches!(decode.receive(Bytes::new(), None), Ok(None)));
+        assert!(matches!(decode.receive(Bytes::from_static(b"{}"), None), Ok(None)));
+
+        assert!(METRICS.receive_empty.get() > before);
+    }
+
+    #[test]
+    fn build_returns_local_addrs() {
+        let config = Config {
+            bind: "127.0.0.1:0,http://127.0.0.1:0".parse().unwrap(),
+            ..Default::default()
+        };
+
+        let server = build(config, |_| Ok(None), |_| Ok(())).expect("failed to build server");
+
+        let addrs = server.local_addrs();
+        assert_eq!(2, addrs.len());
+
+        for addr in addrs {
+            assert!(addr.ip().is_loopback());
+            assert_ne!(0, addr.port());
+        }
+    }
+
+    #[test]
+    fn missing_hosts_default_by_protocol() {
+        use std::io::{Read, Write};
+
+        use crate::output::OutputSink;
+
+        struct Events(Vec<String>);
+
+        impl OutputSink for Events {
+            fn send(&mut self, event: &str) -> Result<(), Error> {
+                self.0.push(event.to_owned());
+
+                Ok(())
+            }
+        }
+
+        let config = Config {
+            bind: "udp://127.0.0.1:0,http://127.0.0.1:0".parse().unwrap(),
+            metrics_interval_ms: 0,
+            ..Default::default()
+        };
+
+        let (host_tx, host_rx) = std::sync::mpsc::channel();
+        let host_tx = Mutex::new(host_tx);
+
+        let process = crate::process::build(Default::default());
+        let server = build(config, receive_gelf(), move |msg| {
+            let mut events = Events(Vec::new());
+            process.read_as_clef(msg, &mut events)?;
+
+            let event: serde_json::Value = serde_json::from_str(&events.0[0])?;
+            let _ = host_tx.lock().unwrap().send(event["host"].clone());
+
+            Ok(())
+        })
+        .expect("failed to build server");
+
+        let (udp_addr, http_addr) = (server.local_addrs()[0], server.local_addrs()[1]);
+        let handle = server.handle();
+
+        thread::spawn(move || {
+            let _ = tokio::runtime::current_thread::block_on_all(server);
+        });
+
+        let gelf = b"{\"version\":\"1.1\",\"short_message\":\"No host\"}";
+
+        // UDP has no connection, so the host is unknown
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.send_to(gelf, udp_addr).unwrap();
+
+        let host = host_rx.recv_timeout(Duration::from_secs(5)).expect("UDP message wasn't handled");
+        assert_eq!(json!("unknown"), host);
+
+        // HTTP has a connection, so the host is its peer
+        let mut http = std::net::TcpStream::connect(http_addr).unwrap();
+        write!(http, "POST /gelf HTTP/1.1\r\nContent-Length: {}\r\n\r\n", gelf.len()).unwrap();
+        http.write_all(gelf).unwrap();
+        let _ = http.read(&mut [0; 64]);
+
+        let host = host_rx.recv_timeout(Duration::from_secs(5)).expect("HTTP message wasn't handled");
+        assert_eq!(json!("127.0.0.1"), host);
+
+        handle.drain();
+    }
+
+    #[test]
+    fn handle_drains_server() {
+        let config = Config {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            metrics_interval_ms: 0,
+            ..Default::default()
+        };
+
+        let server = build(config, |_| Ok(None), |_| Ok(())).expect("failed to build server");
+        let handle = server.handle();
+
+        assert!(!handle.is_draining());
+        assert!(!handle.is_idle());
+
+        let (exit_tx, exit_rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let exit = tokio::runtime::current_thread::block_on_all(server);
+            let _ = exit_tx.send(matches!(exit, Err(Exit::Clean)));
+        });
+
+        handle.drain();
+
+        let clean = exit_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("server didn't drain");
+
+        assert!(clean);
+        assert!(handle.is_draining());
+        assert!(handle.is_idle());
+    }
+
+    #[test]
+    fn drain_unbinds_diagnostics() {
+        // Find a free port to serve diagnostics on
+        let metrics_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+
+        let config = Config {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            metrics_interval_ms: 0,
+            metrics_bind: Some(metrics_addr.to_string()),
+            ..Default::default()
+        };
+
+        let server = build(config, |_| Ok(None), |_| Ok(())).expect("failed to build server");
+        let handle = server.handle();
+
+        let (exit_tx, exit_rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let exit = tokio::runtime::current_thread::block_on_all(server);
+            let _ = exit_tx.send(matches!(exit, Err(Exit::Clean)));
+        });
+
+        assert!(std::net::TcpStream::connect(metrics_addr).is_ok());
+
+        handle.drain();
+
+        let clean = exit_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("server didn't drain");
+
+        assert!(clean);
+        assert!(std::net::TcpStream::connect(metrics_addr).is_err());
+    }
+
+    #[test]
+    fn server_stops_when_processing_stops() {
+        let config = Config {
+            metrics_interval_ms: 0,
+            ..Default::default()
+        };
+
+        let (tx, server) = build_in_memory(config, receive_gelf(), |_| panic!("processing failed"));
+        let handle = server.handle();
+
+        let (exit_tx, exit_rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let exit = tokio::runtime::current_thread::block_on_all(server);
+            let _ = exit_tx.send(matches!(exit, Err(Exit::Failure)));
+        });
+
+        // Keep sending until the server notices the processing thread is gone
+        let failed = (0..500).find_map(|_| {
+            let _ = tx.unbounded_send(Ok(Bytes::from_static(b"{\"short_message\":\"a message\"}")));
+
+            exit_rx.recv_timeout(Duration::from_millis(10)).ok()
+        });
+
+        assert_eq!(Some(true), failed);
+        assert!(handle.is_draining());
+    }
+
+    #[test]
+    fn shutdown_reasons_are_snake_case() {
+        let reasons = [
+            ShutdownReason::HandleClose,
+            ShutdownReason::CtrlC,
+            ShutdownReason::Sigterm,
+            ShutdownReason::StdinClosed,
+            ShutdownReason::ListenerError,
+            ShutdownReason::DrainTimeout,
+        ];
+
+        assert_eq!(
+            json!([
+                "handle_close",
+                "ctrl_c",
+                "sigterm",
+                "stdin_closed",
+                "listener_error",
+                "drain_timeout",
+            ]),
+            json!(reasons)
+        );
+    }
+
+    #[test]
+    fn udp_drops_oversized_datagrams() {
+        let mut udp = UdpOptions {
+            limit: None,
+            max_size_bytes: 4,
+            split_on_null: false,
+        };
+
+        let addr = "127.0.0.1:12201".parse().unwrap();
+        let before = METRICS.udp_msg_overflow.get();
+
+        assert!(udp.admit(&Bytes::from_static(b"{}"), addr));
+        assert!(udp.admit(&Bytes::from_static(b"{  }"), addr));
+        assert!(!udp.admit(&Bytes::from_static(b"{   }"), addr));
+
+        assert!(METRICS.udp_msg_overflow.get() > before);
+    }
+
+    #[test]
+    fn split_datagrams_on_null() {
+        let frames = |src: &'static [u8]| -> Vec<Bytes> {
+            split_on_null(Bytes::from_static(src)).collect()
+        };
+
+        assert_eq!(vec![Bytes::from_static(b"{\"a\":1}"), Bytes::from_static(b"{\"b\":2}")], frames(b"{\"a\":1}\0{\"b\":2}\0"));
+        assert_eq!(vec![Bytes::from_static(b"{}")], frames(b"{}"));
+
+        // Chunked and compressed datagrams aren't split
+        assert_eq!(vec![Bytes::from_static(b"\x1e\x0f\0\0\x01")], frames(b"\x1e\x0f\0\0\x01"));
+        assert_eq!(vec![Bytes::from_static(b"\x1f\x8b\0{}")], frames(b"\x1f\x8b\0{}"));
+    }
+
+    #[test]
+    fn udp_split_on_null_receives_each_message() {
+        let config = Config::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .udp_split_on_null(true)
+            .metrics_interval_ms(0)
+            .build();
+
+        let (handled_tx, handled_rx) = std::sync::mpsc::channel();
+        let handled_tx = Mutex::new(handled_tx);
+
+        let server = build(config, receive_gelf(), move |msg| {
+            use crate::io::MemRead;
+
+            assert!(msg.source().expect("missing source").ip().is_loopback());
+
+            let msg = String::from_utf8_lossy(msg.bytes().unwrap()).into_owned();
+            let _ = handled_tx.lock().unwrap().send(msg);
+
+            Ok(())
+        })
+        .expect("failed to build server");
+
+        let addr = server.local_addrs()[0];
+        let handle = server.handle();
+
+        thread::spawn(move || {
+            let _ = tokio::runtime::current_thread::block_on_all(server);
+        });
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .send_to(b"{\"short_message\":\"a\"}\0{\"short_message\":\"b\"}\0", addr)
+            .unwrap();
+
+        let recv = || handled_rx.recv_timeout(Duration::from_secs(5)).expect("message wasn't handled");
+
+        assert_eq!("{\"short_message\":\"a\"}", recv());
+        assert_eq!("{\"short_message\":\"b\"}", recv());
+
+        handle.drain();
+    }
+
+    #[test]
+    fn bind_udp_dual_stack_receives_ipv4() {
+        let config = Config::default();
+
+        let server = Listener::bind_udp_socket("[::]:0".parse().unwrap(), &config)
+            .expect("failed to bind server");
+        server.set_nonblocking(false).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let port = server.local_addr().unwrap().port();
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"hello", ("127.0.0.1", port)).unwrap();
+
+        let mut buf = [0; 16];
+        let (len, from) = server.recv_from(&mut buf).expect("failed to receive");
+
+        assert_eq!(b"hello", &buf[..len]);
+
+        match from {
+            SocketAddr::V6(from) => assert_eq!(
+                Some("127.0.0.1".parse().unwrap()),
+                from.ip().to_ipv4_mapped()
+            ),
+            from => panic!("expected an IPv4-mapped address, got {}", from),
+        }
+    }
+}
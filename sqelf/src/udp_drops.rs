@@ -0,0 +1,122 @@
+/*!
+Best-effort monitoring of datagrams dropped by the kernel.
+
+Datagrams that arrive when a UDP socket's receive buffer is full are dropped
+by the kernel before they're ever read. On Linux, these drops are counted per
+socket in `/proc/net/udp` and `/proc/net/udp6`, so this module is only
+built for Linux.
+*/
+
+use std::{collections::HashMap, fs, os::unix::io::RawFd};
+
+/**
+The kernel drop counts for a set of UDP sockets.
+*/
+pub(crate) struct UdpDrops {
+    // The last seen drop count for each socket inode
+    by_inode: HashMap<u64, u64>,
+}
+
+impl UdpDrops {
+    /**
+    Monitor the given UDP sockets.
+
+    Sockets whose inode can't be determined are ignored.
+    */
+    pub(crate) fn new(sockets: &[RawFd]) -> Self {
+        let by_inode = sockets
+            .iter()
+            .filter_map(|fd| socket_inode(*fd))
+            .map(|inode| (inode, 0))
+            .collect();
+
+        UdpDrops { by_inode }
+    }
+
+    /**
+    Whether there are any sockets to monitor.
+    */
+    pub(crate) fn is_empty(&self) -> bool {
+        self.by_inode.is_empty()
+    }
+
+    /**
+    Read the current drop counts, returning the number of new drops
+    since they were last read.
+    */
+    pub(crate) fn sample(&mut self) -> u64 {
+        let mut new_drops = 0;
+
+        for table in &["/proc/net/udp", "/proc/net/udp6"] {
+            let table = match fs::read_to_string(table) {
+                Ok(table) => table,
+                Err(_) => continue,
+            };
+
+            for (inode, drops) in parse_table(&table) {
+                if let Some(last) = self.by_inode.get_mut(&inode) {
+                    new_drops += drops.saturating_sub(*last);
+                    *last = drops;
+                }
+            }
+        }
+
+        new_drops
+    }
+}
+
+/**
+Get the inode of a socket from its file descriptor.
+
+The link for a socket in `/proc/self/fd` looks like `socket:[12345]`.
+*/
+fn socket_inode(fd: RawFd) -> Option<u64> {
+    let link = fs::read_link(format!("/proc/self/fd/{}", fd)).ok()?;
+    let link = link.to_str()?;
+
+    link.strip_prefix("socket:[")?.strip_suffix(']')?.parse().ok()
+}
+
+/**
+Read the inode and drop count of each socket in a `/proc/net/udp` table.
+*/
+fn parse_table(table: &str) -> impl Iterator<Item = (u64, u64)> + '_ {
+    const INODE: usize = 9;
+    const DROPS: usize = 12;
+
+    table.lines().skip(1).filter_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+
+        let inode = fields.get(INODE)?.parse().ok()?;
+        let drops = fields.get(DROPS)?.parse().ok()?;
+
+        Some((inode, drops))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_proc_net_udp() {
+        let table = "   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops\n\
+                     \x20 131: 00000000:2F99 00000000:0000 07 00000000:00000000 00:00000000 00000000  1000        0 48291 2 0000000000000000 17\n\
+                     \x20 200: 0100007F:0035 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 1201 2 0000000000000000 0\n";
+
+        let parsed: Vec<_> = parse_table(table).collect();
+
+        assert_eq!(vec![(48291, 17), (1201, 0)], parsed);
+    }
+
+    #[test]
+    fn sample_bound_socket() {
+        use std::os::unix::io::AsRawFd;
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut drops = UdpDrops::new(&[socket.as_raw_fd()]);
+
+        assert!(!drops.is_empty());
+        assert_eq!(0, drops.sample());
+    }
+}